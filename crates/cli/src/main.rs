@@ -0,0 +1,768 @@
+//! Command-line client for the Astra file server, so build scripts can pull and push data
+//! files without Astra. With `--json` every command prints a single JSON value, and
+//! failures are printed to stderr as `{"error": {...}}`.
+
+use anyhow::{bail, Context, Result};
+use cobalt_fs_client::sync::{self, Change, SyncAction};
+use cobalt_fs_client::{Client, ClientError, ClientOptions, EntryKind, FileHash};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: cobalt-fs [OPTIONS] <COMMAND>
+
+Commands:
+  ls <DIR>                 List every file below DIR in rom:/Data.
+  get <PATH> [LOCAL]       Download a file. LOCAL defaults to its name in the current
+                           directory, and - writes it to stdout.
+  get -r <DIR> [LOCAL]     Download every file below DIR into the directory LOCAL.
+  put <LOCAL> <PATH>       Upload a file to PATH in the mod root.
+  stat <PATH>...           Describe entries in rom:/Data.
+  hash <PATH>...           SHA-256 of files in rom:/Data.
+  sync <LOCAL> <DIR>       Upload the files of the folder LOCAL that differ from those
+                           below DIR in the mod root.
+
+Options:
+  --host <HOST>      Console address. Defaults to $COBALT_FS_HOST.
+  --port <PORT>      Server port [default: 7878].
+  --token <TOKEN>    Authentication token. Defaults to $COBALT_FS_TOKEN.
+  --timeout <SECS>   Network timeout [default: 30].
+  --glob <GLOB>      Only list or download paths matching GLOB, relative to DIR.
+  -r, --recursive    Download a whole directory.
+  --dry-run          Only print what sync would change.
+  --delete           Let sync delete remote files that are missing locally, and
+                     the directories that leaves empty.
+  --json             Print JSON instead of text.";
+
+const DEFAULT_PORT: u16 = 7878;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    List {
+        dir: String,
+        glob: String,
+    },
+    Get {
+        path: String,
+        local: Option<PathBuf>,
+        recursive: bool,
+        glob: String,
+    },
+    Put {
+        local: PathBuf,
+        path: String,
+    },
+    Stat {
+        paths: Vec<String>,
+    },
+    Hash {
+        paths: Vec<String>,
+    },
+    Sync {
+        local: PathBuf,
+        dir: String,
+        dry_run: bool,
+        delete: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Args {
+    host: String,
+    port: u16,
+    options: ClientOptions,
+    json: bool,
+    command: Command,
+}
+
+impl Args {
+    /// Options may appear before or after the command. `env` looks up the environment
+    /// variables used as defaults.
+    fn parse<I, E>(mut args: I, env: E) -> Result<Self>
+    where
+        I: Iterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let mut host = env("COBALT_FS_HOST");
+        let mut port = DEFAULT_PORT;
+        let mut options = ClientOptions {
+            token: env("COBALT_FS_TOKEN").filter(|token| !token.is_empty()),
+            ..ClientOptions::default()
+        };
+        let mut json = false;
+        let mut glob = None;
+        let mut recursive = false;
+        let mut dry_run = false;
+        let mut delete = false;
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--host" => host = Some(value()?),
+                "--port" => port = value()?.parse().context("Invalid port")?,
+                "--token" => options.token = Some(value()?),
+                "--timeout" => {
+                    let secs = value()?.parse().context("Invalid timeout")?;
+                    options.timeout = Some(Duration::from_secs(secs));
+                }
+                "--glob" => glob = Some(value()?),
+                "-r" | "--recursive" => recursive = true,
+                "--dry-run" => dry_run = true,
+                "--delete" => delete = true,
+                "--json" => json = true,
+                "-h" | "--help" => bail!("{}", USAGE),
+                "-" => positional.push(arg),
+                _ if arg.starts_with('-') => bail!("Unknown option {}\n\n{}", arg, USAGE),
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let name = positional
+            .next()
+            .with_context(|| format!("Missing command\n\n{}", USAGE))?;
+        let operands: Vec<String> = positional.collect();
+        if glob.is_some() && !(name == "ls" || name == "get" && recursive) {
+            bail!("--glob only applies to ls and get -r");
+        }
+        if recursive && name != "get" {
+            bail!("-r only applies to get");
+        }
+        if (dry_run || delete) && name != "sync" {
+            bail!("--dry-run and --delete only apply to sync");
+        }
+        let glob = glob.unwrap_or_default();
+        let command = match (name.as_str(), operands.as_slice()) {
+            ("ls", [dir]) => Command::List {
+                dir: dir.clone(),
+                glob,
+            },
+            ("get", [path]) => Command::Get {
+                path: path.clone(),
+                local: None,
+                recursive,
+                glob,
+            },
+            ("get", [path, local]) => Command::Get {
+                path: path.clone(),
+                local: Some(local.into()),
+                recursive,
+                glob,
+            },
+            ("put", [local, path]) => Command::Put {
+                local: local.into(),
+                path: path.clone(),
+            },
+            ("stat", paths) if !paths.is_empty() => Command::Stat {
+                paths: paths.to_vec(),
+            },
+            ("hash", paths) if !paths.is_empty() => Command::Hash {
+                paths: paths.to_vec(),
+            },
+            ("sync", [local, dir]) => Command::Sync {
+                local: local.into(),
+                dir: dir.clone(),
+                dry_run,
+                delete,
+            },
+            ("ls" | "get" | "put" | "stat" | "hash" | "sync", _) => {
+                bail!("Wrong arguments for {}\n\n{}", name, USAGE)
+            }
+            _ => bail!("Unknown command {}\n\n{}", name, USAGE),
+        };
+        if json
+            && matches!(&command, Command::Get { local: Some(local), .. } if local == Path::new("-"))
+        {
+            bail!("--json can't be combined with writing a file to stdout");
+        }
+
+        Ok(Self {
+            host: host.context("No host given, pass --host or set COBALT_FS_HOST")?,
+            port,
+            options,
+            json,
+            command,
+        })
+    }
+}
+
+/// What a command prints, as JSON or as one line per record.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Output {
+    Paths(Vec<String>),
+    Transfers(Vec<Transfer>),
+    Stats(Vec<Stat>),
+    Hashes(Vec<Hash>),
+    Sync(SyncReport),
+    /// A file was written to stdout, so nothing else may be printed there.
+    Nothing,
+}
+
+#[derive(Debug, Serialize)]
+struct Transfer {
+    remote: String,
+    local: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Stat {
+    path: String,
+    kind: &'static str,
+    size: u64,
+    /// Seconds since the Unix epoch.
+    modified: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Hash {
+    path: String,
+    sha256: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SyncReport {
+    dry_run: bool,
+    /// Carried out, or with `dry_run` only planned.
+    actions: Vec<SyncStep>,
+    unchanged: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum SyncStep {
+    Upload {
+        path: String,
+        local: String,
+        size: u64,
+        /// `new` or `modified`.
+        change: &'static str,
+    },
+    Delete {
+        path: String,
+    },
+    #[serde(rename = "delete_directory")]
+    DeleteDirectory {
+        path: String,
+    },
+}
+
+impl From<&SyncAction> for SyncStep {
+    fn from(action: &SyncAction) -> Self {
+        match action {
+            SyncAction::Upload {
+                local,
+                remote,
+                size,
+                change,
+            } => SyncStep::Upload {
+                path: remote.clone(),
+                local: local.display().to_string(),
+                size: *size,
+                change: match change {
+                    Change::New => "new",
+                    Change::Modified => "modified",
+                },
+            },
+            SyncAction::Delete { remote } => SyncStep::Delete {
+                path: remote.clone(),
+            },
+            SyncAction::DeleteDirectory { remote } => SyncStep::DeleteDirectory {
+                path: remote.clone(),
+            },
+        }
+    }
+}
+
+impl Output {
+    fn print<W: Write>(&self, writer: &mut W, json: bool) -> io::Result<()> {
+        if json {
+            if !matches!(self, Output::Nothing) {
+                serde_json::to_writer_pretty(&mut *writer, self)?;
+                writeln!(writer)?;
+            }
+            return Ok(());
+        }
+        match self {
+            Output::Paths(paths) => {
+                for path in paths {
+                    writeln!(writer, "{}", path)?;
+                }
+            }
+            Output::Transfers(transfers) => {
+                for transfer in transfers {
+                    writeln!(
+                        writer,
+                        "{} -> {} ({} bytes)",
+                        transfer.remote, transfer.local, transfer.size
+                    )?;
+                }
+            }
+            Output::Stats(stats) => {
+                for stat in stats {
+                    let modified = stat
+                        .modified
+                        .map_or("-".to_string(), |time| time.to_string());
+                    writeln!(
+                        writer,
+                        "{}\t{}\t{}\t{}",
+                        stat.kind, stat.size, modified, stat.path
+                    )?;
+                }
+            }
+            Output::Hashes(hashes) => {
+                for hash in hashes {
+                    writeln!(
+                        writer,
+                        "{}  {}",
+                        hash.sha256.as_deref().unwrap_or("-"),
+                        hash.path
+                    )?;
+                }
+            }
+            Output::Sync(report) => {
+                for step in &report.actions {
+                    match step {
+                        SyncStep::Upload {
+                            path, size, change, ..
+                        } => writeln!(writer, "upload {} {} ({} bytes)", change, path, size)?,
+                        SyncStep::Delete { path } => writeln!(writer, "delete {}", path)?,
+                        SyncStep::DeleteDirectory { path } => {
+                            writeln!(writer, "delete directory {}", path)?
+                        }
+                    }
+                }
+                writeln!(
+                    writer,
+                    "{} {}, {} unchanged",
+                    report.actions.len(),
+                    if report.dry_run {
+                        "changes planned"
+                    } else {
+                        "changes made"
+                    },
+                    report.unchanged
+                )?;
+            }
+            Output::Nothing => {}
+        }
+        Ok(())
+    }
+}
+
+fn run(client: &mut Client, command: &Command) -> Result<Output> {
+    match command {
+        Command::List { dir, glob } => {
+            let mut paths = client.list(dir, glob)?;
+            paths.sort();
+            Ok(Output::Paths(paths))
+        }
+        Command::Get {
+            path,
+            local,
+            recursive: false,
+            ..
+        } => {
+            if local.as_deref() == Some(Path::new("-")) {
+                let mut stdout = io::stdout().lock();
+                client.read_to(path, &mut stdout)?;
+                stdout.flush()?;
+                return Ok(Output::Nothing);
+            }
+            let local = match local {
+                Some(local) if local.is_dir() => local.join(file_name(path)?),
+                Some(local) => local.clone(),
+                None => PathBuf::from(file_name(path)?),
+            };
+            Ok(Output::Transfers(vec![download(client, path, &local)?]))
+        }
+        Command::Get {
+            path,
+            local,
+            recursive: true,
+            glob,
+        } => {
+            let local = match local {
+                Some(local) => local.clone(),
+                None => PathBuf::from(file_name(path).unwrap_or(".")),
+            };
+            let mut remotes = client.list(path, glob)?;
+            remotes.sort();
+            let transfers = remotes
+                .iter()
+                .map(|remote| download(client, remote, &local.join(relative_to(path, remote)?)))
+                .collect::<Result<_>>()?;
+            Ok(Output::Transfers(transfers))
+        }
+        Command::Put { local, path } => {
+            let file =
+                File::open(local).with_context(|| format!("Could not open {}", local.display()))?;
+            let size = file.metadata()?.len();
+            let hash = client.write_from(path, &mut BufReader::new(file), size)?;
+            Ok(Output::Transfers(vec![Transfer {
+                remote: path.clone(),
+                local: local.display().to_string(),
+                size,
+                sha256: hex(&hash),
+            }]))
+        }
+        Command::Stat { paths } => {
+            let stats = client.stat_batch(paths)?;
+            Ok(Output::Stats(
+                paths
+                    .iter()
+                    .zip(stats)
+                    .map(|(path, stat)| Stat {
+                        path: path.clone(),
+                        kind: match stat.kind {
+                            EntryKind::Missing => "missing",
+                            EntryKind::File => "file",
+                            EntryKind::Directory => "directory",
+                        },
+                        size: stat.size,
+                        modified: stat.modified,
+                    })
+                    .collect(),
+            ))
+        }
+        Command::Hash { paths } => {
+            let hashes = client.hash_batch(paths)?;
+            Ok(Output::Hashes(
+                paths
+                    .iter()
+                    .zip(hashes)
+                    .map(|(path, hash)| Hash {
+                        path: path.clone(),
+                        sha256: hash.as_ref().map(hex),
+                    })
+                    .collect(),
+            ))
+        }
+        Command::Sync {
+            local,
+            dir,
+            dry_run,
+            delete,
+        } => {
+            if !local.is_dir() {
+                bail!("{} is not a directory", local.display());
+            }
+            let plan = sync::plan(client, local, dir, *delete)?;
+            let mut actions = Vec::new();
+            if *dry_run {
+                actions.extend(plan.actions.iter().map(SyncStep::from));
+            } else {
+                let result = sync::apply(client, &plan, |action| actions.push(action.into()));
+                result.with_context(|| {
+                    format!(
+                        "Sync stopped after {} of {} changes",
+                        actions.len(),
+                        plan.actions.len()
+                    )
+                })?;
+            }
+            Ok(Output::Sync(SyncReport {
+                dry_run: *dry_run,
+                actions,
+                unchanged: plan.unchanged.len(),
+            }))
+        }
+    }
+}
+
+/// Downloads `remote` next to `local` first and renames it into place once complete, so
+/// an interrupted download never leaves a truncated file behind.
+fn download(client: &mut Client, remote: &str, local: &Path) -> Result<Transfer> {
+    if let Some(parent) = local
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create {}", parent.display()))?;
+    }
+    let mut partial = OsString::from(local);
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let result = receive(client, remote, &partial, local);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result.with_context(|| format!("Could not download {}", remote))
+}
+
+fn receive(client: &mut Client, remote: &str, partial: &Path, local: &Path) -> Result<Transfer> {
+    let file =
+        File::create(partial).with_context(|| format!("Could not create {}", partial.display()))?;
+    let mut writer = HashingWriter {
+        inner: BufWriter::new(file),
+        digest: Sha256::new(),
+    };
+    let size = client.read_to(remote, &mut writer)?;
+    writer
+        .inner
+        .into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    std::fs::rename(partial, local)
+        .with_context(|| format!("Could not move {} into place", partial.display()))?;
+    let hash: FileHash = writer.digest.finalize().into();
+    Ok(Transfer {
+        remote: remote.to_string(),
+        local: local.display().to_string(),
+        size,
+        sha256: hex(&hash),
+    })
+}
+
+/// The last component of a remote path.
+fn file_name(path: &str) -> Result<&str> {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+        .with_context(|| format!("{} does not name a file", path))
+}
+
+/// Maps a listed path onto the directory it was listed from. The directory is normalized
+/// like the server lists it, so `./xml/` matches paths listed as `xml/...`. The server
+/// only lists paths below that directory, but a path outside it or one that would climb
+/// out of the download directory is refused rather than trusted.
+fn relative_to<'a>(dir: &str, path: &'a str) -> Result<&'a Path> {
+    let dir = sync::normalize(dir)?;
+    let relative = match dir.as_str() {
+        "" => Some(path),
+        _ => path
+            .strip_prefix(dir.as_str())
+            .and_then(|rest| rest.strip_prefix('/')),
+    };
+    relative
+        .map(Path::new)
+        .filter(|relative| {
+            !relative.as_os_str().is_empty()
+                && relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+        })
+        .with_context(|| format!("The server listed an unsafe path {:?}", path))
+}
+
+fn hex(hash: &FileHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    digest: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Describes a failure for scripts: the server's error code when it sent one, otherwise
+/// the kind of client failure.
+fn error_json(err: &anyhow::Error) -> serde_json::Value {
+    let client = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ClientError>());
+    let (code, context) = match client {
+        Some(ClientError::Server(err)) => (format!("{:?}", err.code), err.context.clone()),
+        Some(ClientError::Io(_)) => ("Io".to_string(), None),
+        Some(ClientError::Protocol(_)) => ("Protocol".to_string(), None),
+        Some(ClientError::UnsupportedVersion(_)) => ("UnsupportedVersion".to_string(), None),
+        Some(ClientError::AuthenticationRequired) => ("AuthenticationRequired".to_string(), None),
+        Some(ClientError::InvalidArgument(_)) | None => ("Local".to_string(), None),
+    };
+    serde_json::json!({
+        "error": {
+            "code": code,
+            "message": format!("{:#}", err),
+            "context": context,
+        }
+    })
+}
+
+fn main() -> ExitCode {
+    let json = std::env::args().any(|arg| arg == "--json");
+    let result =
+        Args::parse(std::env::args().skip(1), |name| std::env::var(name).ok()).and_then(|args| {
+            let mut client = Client::connect((args.host.as_str(), args.port), args.options)
+                .with_context(|| format!("Could not connect to {}:{}", args.host, args.port))?;
+            let output = run(&mut client, &args.command)?;
+            output.print(&mut io::stdout().lock(), args.json)?;
+            let _ = client.close();
+            Ok(())
+        });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            if json {
+                eprintln!("{}", error_json(&err));
+            } else {
+                eprintln!("Error: {:#}", err);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cobalt_fs_client::test_support::TestServer;
+    use cobalt_fs_server::config::ServerConfig;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()), |name| {
+            (name == "COBALT_FS_HOST").then(|| "switch.local".to_string())
+        })
+    }
+
+    #[test]
+    fn options_may_follow_the_command() {
+        let args = parse(&["get", "xml", "--port", "9000", "-r", "out", "--json"]).unwrap();
+        assert_eq!(args.host, "switch.local");
+        assert_eq!(args.port, 9000);
+        assert!(args.json);
+        assert_eq!(
+            args.command,
+            Command::Get {
+                path: "xml".to_string(),
+                local: Some("out".into()),
+                recursive: true,
+                glob: String::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_arguments_are_refused() {
+        assert!(parse(&["ls"]).is_err());
+        assert!(parse(&["ls", "a", "b"]).is_err());
+        assert!(parse(&["stat"]).is_err());
+        assert!(parse(&["put", "a.xml"]).is_err());
+        assert!(parse(&["frobnicate", "a"]).is_err());
+        assert!(parse(&["stat", "a", "--glob", "*.xml"]).is_err());
+        assert!(parse(&["ls", "a", "-r"]).is_err());
+        assert!(parse(&["put", "a", "b", "--delete"]).is_err());
+        assert!(parse(&["sync", "a"]).is_err());
+        assert!(parse(&["get", "a.xml", "-", "--json"]).is_err());
+        assert!(Args::parse(["ls".to_string(), "a".to_string()].into_iter(), |_| None).is_err());
+    }
+
+    #[test]
+    fn listed_paths_may_not_leave_the_download_directory() {
+        assert_eq!(
+            relative_to("xml/", "xml/a/b.xml").unwrap(),
+            Path::new("a/b.xml")
+        );
+        assert_eq!(
+            relative_to("./xml/", "xml/a/b.xml").unwrap(),
+            Path::new("a/b.xml")
+        );
+        assert_eq!(
+            relative_to("", "xml/b.xml").unwrap(),
+            Path::new("xml/b.xml")
+        );
+        assert_eq!(
+            relative_to(".", "xml/b.xml").unwrap(),
+            Path::new("xml/b.xml")
+        );
+        assert!(relative_to("xml", "other/b.xml").is_err());
+        assert!(relative_to("xml/..", "xml/b.xml").is_err());
+        assert!(relative_to("xml", "xml/../../etc/passwd").is_err());
+        assert!(relative_to("xml", "/etc/passwd").is_err());
+        assert!(relative_to("xml", "xml").is_err());
+    }
+
+    #[test]
+    fn downloads_directories_and_uploads_files() {
+        let server = TestServer::start(ServerConfig::default());
+        let (data, mods) = (server.data.path(), server.mods.path());
+        let local = tempfile::tempdir().unwrap();
+        std::fs::create_dir(data.join("xml/sub")).unwrap();
+        std::fs::write(data.join("xml/sub/b.xml"), b"bb").unwrap();
+        std::fs::write(data.join("xml/sub/c.txt"), b"ccc").unwrap();
+        let mut client = server.connect();
+
+        let out = local.path().join("out");
+        let Output::Transfers(transfers) = run(
+            &mut client,
+            &Command::Get {
+                path: "./xml/".to_string(),
+                local: Some(out.clone()),
+                recursive: true,
+                glob: "**/*.xml".to_string(),
+            },
+        )
+        .unwrap() else {
+            panic!("expected transfers");
+        };
+        assert_eq!(transfers.len(), 3);
+        assert_eq!(std::fs::read(out.join("God.xml")).unwrap(), b"<God/>");
+        assert_eq!(std::fs::read(out.join("sub/b.xml")).unwrap(), b"bb");
+        assert!(!out.join("sub/c.txt").exists());
+        assert_eq!(transfers[2].sha256, hex(&Sha256::digest(b"bb").into()));
+
+        run(
+            &mut client,
+            &Command::Put {
+                local: out.join("sub/b.xml"),
+                path: "xml/b.xml".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(mods.join("xml/b.xml")).unwrap(), b"bb");
+
+        for dry_run in [true, false] {
+            let Output::Sync(report) = run(
+                &mut client,
+                &Command::Sync {
+                    local: out.clone(),
+                    dir: "xml".to_string(),
+                    dry_run,
+                    delete: true,
+                },
+            )
+            .unwrap() else {
+                panic!("expected a sync report");
+            };
+            assert_eq!(report.actions.len(), 4);
+            assert_eq!(mods.join("xml/God.xml").exists(), !dry_run);
+        }
+        assert!(!mods.join("xml/b.xml").exists());
+
+        let err = run(
+            &mut client,
+            &Command::Get {
+                path: "xml/missing.xml".to_string(),
+                local: Some(local.path().join("missing.xml")),
+                recursive: false,
+                glob: String::new(),
+            },
+        )
+        .unwrap_err();
+        assert_eq!(error_json(&err)["error"]["code"], "NotFound");
+        assert!(!local.path().join("missing.xml.part").exists());
+    }
+}
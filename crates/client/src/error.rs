@@ -0,0 +1,82 @@
+//! Errors returned by the client, keeping the server's error frames apart from failures
+//! of the connection itself.
+
+use cobalt_fs_server::error::{ErrorCode, ServerError};
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The connection failed or timed out.
+    Io(io::Error),
+    /// The server answered with an error frame.
+    Server(ServerError),
+    /// The server sent something that doesn't follow the protocol.
+    Protocol(String),
+    /// The server doesn't speak this client's protocol version.
+    UnsupportedVersion(u16),
+    /// The server requires a token and none was configured.
+    AuthenticationRequired,
+    /// An argument can't be sent, for example a path containing a line break.
+    InvalidArgument(String),
+}
+
+impl ClientError {
+    /// The code of the server's error frame, if the server sent one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            ClientError::Server(err) => Some(err.code),
+            _ => None,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ClientError::Io(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "Connection failed: {}", err),
+            ClientError::Server(err) => write!(f, "Server error {}", err),
+            ClientError::Protocol(message) => write!(f, "Protocol violation: {}", message),
+            ClientError::UnsupportedVersion(version) => write!(
+                f,
+                "The server speaks protocol version {}, which this client does not support",
+                version
+            ),
+            ClientError::AuthenticationRequired => {
+                write!(f, "The server requires a token, none was given")
+            }
+            ClientError::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+impl From<ServerError> for ClientError {
+    fn from(err: ServerError) -> Self {
+        ClientError::Server(err)
+    }
+}
+
+/// The protocol module decodes frames into `anyhow` errors. I/O failures stay I/O
+/// failures, anything else means the frame was malformed.
+impl From<anyhow::Error> for ClientError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<io::Error>() {
+            Ok(err) => ClientError::Io(err),
+            Err(err) => ClientError::Protocol(format!("{:#}", err)),
+        }
+    }
+}
@@ -0,0 +1,757 @@
+//! Client for the Astra file server. A [`Client`] keeps one negotiated session open and
+//! sends every call over it, reconnecting when the server has closed it in between.
+//!
+//! Paths are relative to the server's `rom:/Data` for reads and to its mod root for
+//! writes, as described in [`cobalt_fs_server::protocol`].
+
+mod error;
+pub mod sync;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+pub use cobalt_fs_server::error::{ErrorCode, ServerError};
+pub use cobalt_fs_server::protocol::{DirEntry, EntryKind, FileHash, FileStat, ServerHello};
+pub use error::{ClientError, Result};
+
+use cobalt_fs_server::auth;
+use cobalt_fs_server::protocol::{
+    self, HandshakeStatus, Limit, ReadExt, Request, MAX_BATCH_PATHS, MAX_GLOB_LENGTH,
+    MAX_PATH_LENGTH, PROTOCOL_VERSION, STATUS_ERROR, STATUS_OK,
+};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Token to answer the server's challenge with when it requires authentication.
+    pub token: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    /// Longest wait for any single read or write on the connection.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            token: None,
+            connect_timeout: Some(Duration::from_secs(5)),
+            timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+pub struct Client {
+    address: SocketAddr,
+    options: ClientOptions,
+    session: Option<Session>,
+}
+
+impl Client {
+    /// Connects and completes the handshake, so a wrong address or token fails here
+    /// rather than on the first call.
+    pub fn connect<A: ToSocketAddrs>(address: A, options: ClientOptions) -> Result<Self> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            ClientError::InvalidArgument("The address does not resolve".to_string())
+        })?;
+        let session = Session::open(address, &options)?;
+        Ok(Self {
+            address,
+            options,
+            session: Some(session),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The hello of the server the current session is connected to.
+    pub fn server_hello(&mut self) -> Result<&ServerHello> {
+        Ok(&self.session()?.hello)
+    }
+
+    pub fn exists(&mut self, path: &str) -> Result<bool> {
+        let request = Request::Exists { path: line(path)? };
+        self.call(&request, |session| Ok(session.response()?.read_u8()? != 0))
+    }
+
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.read_to(path, &mut contents)?;
+        Ok(contents)
+    }
+
+    /// Streams the file at `path` into `writer` and returns its length.
+    pub fn read_to<W: Write + ?Sized>(&mut self, path: &str, writer: &mut W) -> Result<u64> {
+        let request = Request::Read { path: line(path)? };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            let length = reader.read_u64()?;
+            copy_exact(reader, writer, length)?;
+            Ok(length)
+        })
+    }
+
+    pub fn read_range(&mut self, path: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let request = Request::ReadRange {
+            path: line(path)?,
+            offset,
+            length,
+        };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            let sent = reader.read_u64()?;
+            if sent != length {
+                return Err(ClientError::Protocol(format!(
+                    "Asked for {} bytes and got {}",
+                    length, sent
+                )));
+            }
+            let mut contents = Vec::new();
+            copy_exact(reader, &mut contents, sent)?;
+            Ok(contents)
+        })
+    }
+
+    /// Lists every file below `dir`, relative to `rom:/Data`. `glob` holds
+    /// comma-separated patterns relative to `dir`, where a leading `!` excludes matches.
+    /// An empty glob lists everything.
+    pub fn list(&mut self, dir: &str, glob: &str) -> Result<Vec<String>> {
+        let request = Request::List {
+            path: line(dir)?,
+            glob: glob_line(glob)?,
+        };
+        self.list_with(&request)
+    }
+
+    /// Lists every file below `dir` of the mod root, relative to the mod root. An empty
+    /// `dir` lists the whole mod root, which is empty if it doesn't exist yet.
+    pub fn mod_list(&mut self, dir: &str, glob: &str) -> Result<Vec<String>> {
+        let request = Request::ModList {
+            path: line(dir)?,
+            glob: glob_line(glob)?,
+        };
+        self.list_with(&request)
+    }
+
+    /// Lists the immediate children of `dir` in `rom:/Data`, sorted by name.
+    pub fn list_dir(&mut self, dir: &str) -> Result<Vec<DirEntry>> {
+        let request = Request::ListDir { path: line(dir)? };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            let count = reader.read_u32()?;
+            (0..count).map(|_| Ok(DirEntry::read(reader)?)).collect()
+        })
+    }
+
+    pub fn stat(&mut self, path: &str) -> Result<FileStat> {
+        let request = Request::Stat { path: line(path)? };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            Ok(FileStat::read(reader)?)
+        })
+    }
+
+    /// Stats every path, split into as many requests as the server's batch limit needs.
+    /// Missing paths are reported with [`EntryKind::Missing`].
+    pub fn stat_batch<S: AsRef<str>>(&mut self, paths: &[S]) -> Result<Vec<FileStat>> {
+        self.stat_batch_with(paths, |paths| Request::StatBatch { paths })
+    }
+
+    /// [`Client::stat_batch`] for paths in the mod root.
+    pub fn mod_stat_batch<S: AsRef<str>>(&mut self, paths: &[S]) -> Result<Vec<FileStat>> {
+        self.stat_batch_with(paths, |paths| Request::ModStatBatch { paths })
+    }
+
+    pub fn hash(&mut self, path: &str) -> Result<FileHash> {
+        let request = Request::Hash { path: line(path)? };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            read_hash(reader)
+        })
+    }
+
+    /// Hashes every path, split like [`Client::stat_batch`]. Missing files and directories
+    /// are `None`.
+    pub fn hash_batch<S: AsRef<str>>(&mut self, paths: &[S]) -> Result<Vec<Option<FileHash>>> {
+        self.hash_batch_with(paths, |paths| Request::HashBatch { paths })
+    }
+
+    /// [`Client::hash_batch`] for paths in the mod root.
+    pub fn mod_hash_batch<S: AsRef<str>>(&mut self, paths: &[S]) -> Result<Vec<Option<FileHash>>> {
+        self.hash_batch_with(paths, |paths| Request::ModHashBatch { paths })
+    }
+
+    /// Fetches the file only if its hash differs from `cached`, returning its new hash
+    /// and contents.
+    pub fn read_if_changed(
+        &mut self,
+        path: &str,
+        cached: &FileHash,
+    ) -> Result<Option<(FileHash, Vec<u8>)>> {
+        let request = Request::ReadIfChanged {
+            path: line(path)?,
+            hash: *cached,
+        };
+        self.call(&request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            if reader.read_u8()? == 0 {
+                return Ok(None);
+            }
+            let hash = read_hash(reader)?;
+            let length = reader.read_u64()?;
+            let mut contents = Vec::new();
+            copy_exact(reader, &mut contents, length)?;
+            Ok(Some((hash, contents)))
+        })
+    }
+
+    /// Uploads `contents` to `path` in the mod root and returns its hash.
+    pub fn write(&mut self, path: &str, contents: &[u8]) -> Result<FileHash> {
+        self.write_from(path, &mut &contents[..], contents.len() as u64)
+    }
+
+    /// Uploads `length` bytes read from `reader` to `path` in the mod root. The hash the
+    /// server computed over what it stored is checked against the bytes that were sent.
+    pub fn write_from<R: Read + ?Sized>(
+        &mut self,
+        path: &str,
+        reader: &mut R,
+        length: u64,
+    ) -> Result<FileHash> {
+        let request = Request::Write {
+            path: line(path)?,
+            length,
+        };
+        self.call(&request, |session| {
+            let mut source = HashingReader {
+                inner: reader,
+                digest: Sha256::new(),
+            };
+            copy_exact(&mut source, &mut session.writer, length)?;
+            let sent: FileHash = source.digest.finalize().into();
+
+            let reader = session.response()?;
+            read_status(reader)?;
+            let written = reader.read_u64()?;
+            let hash = read_hash(reader)?;
+            if written != length || hash != sent {
+                return Err(ClientError::Protocol(format!(
+                    "The server stored {} bytes that differ from the {} bytes sent",
+                    written, length
+                )));
+            }
+            Ok(hash)
+        })
+    }
+
+    pub fn delete_file(&mut self, path: &str) -> Result<()> {
+        let request = Request::DeleteFile { path: line(path)? };
+        self.call(&request, |session| read_status(session.response()?))
+    }
+
+    /// Deletes a directory in the mod root. Without `recursive` it must be empty.
+    pub fn delete_directory(&mut self, path: &str, recursive: bool) -> Result<()> {
+        let request = Request::DeleteDirectory {
+            path: line(path)?,
+            recursive,
+        };
+        self.call(&request, |session| read_status(session.response()?))
+    }
+
+    /// Moves a file or directory within the mod root. Fails if `to` exists.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let request = Request::Rename {
+            from: line(from)?,
+            to: line(to)?,
+        };
+        self.call(&request, |session| read_status(session.response()?))
+    }
+
+    /// Creates a directory in the mod root along with any missing parents.
+    pub fn make_directory(&mut self, path: &str) -> Result<()> {
+        let request = Request::MakeDirectory { path: line(path)? };
+        self.call(&request, |session| read_status(session.response()?))
+    }
+
+    /// Ends the session politely. Dropping the client closes the connection as well.
+    pub fn close(mut self) -> Result<()> {
+        match self.session.take() {
+            Some(mut session) if !session.is_stale() => {
+                Request::Goodbye.write(&mut session.writer)?;
+                read_status(session.response()?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut Session> {
+        let session = match self.session.take() {
+            Some(session) if !session.is_stale() => session,
+            _ => Session::open(self.address, &self.options)?,
+        };
+        Ok(self.session.insert(session))
+    }
+
+    /// Sends `request` and decodes the response with `respond`. After anything but an
+    /// error frame the position in the stream is unknown, so the session is dropped and
+    /// the next call reconnects.
+    fn call<T>(
+        &mut self,
+        request: &Request,
+        respond: impl FnOnce(&mut Session) -> Result<T>,
+    ) -> Result<T> {
+        let session = self.session()?;
+        let result = match request.write(&mut session.writer) {
+            Ok(()) => respond(session),
+            Err(err) => Err(err.into()),
+        };
+        if matches!(&result, Err(err) if !matches!(err, ClientError::Server(_))) {
+            self.session = None;
+        }
+        result
+    }
+
+    fn list_with(&mut self, request: &Request) -> Result<Vec<String>> {
+        self.call(request, |session| {
+            let reader = session.response()?;
+            read_status(reader)?;
+            let count = reader.read_u64()?;
+            (0..count).map(|_| read_line(reader)).collect()
+        })
+    }
+
+    fn stat_batch_with<S: AsRef<str>>(
+        &mut self,
+        paths: &[S],
+        request: fn(Vec<String>) -> Request,
+    ) -> Result<Vec<FileStat>> {
+        let mut stats = Vec::with_capacity(paths.len());
+        for chunk in paths.chunks(self.batch_size()?) {
+            let request = request(lines(chunk)?);
+            self.call(&request, |session| {
+                let reader = session.response()?;
+                read_status(reader)?;
+                read_count(reader, chunk.len())?;
+                for _ in chunk {
+                    stats.push(FileStat::read(reader)?);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(stats)
+    }
+
+    fn hash_batch_with<S: AsRef<str>>(
+        &mut self,
+        paths: &[S],
+        request: fn(Vec<String>) -> Request,
+    ) -> Result<Vec<Option<FileHash>>> {
+        let mut hashes = Vec::with_capacity(paths.len());
+        for chunk in paths.chunks(self.batch_size()?) {
+            let request = request(lines(chunk)?);
+            self.call(&request, |session| {
+                let reader = session.response()?;
+                read_status(reader)?;
+                read_count(reader, chunk.len())?;
+                for _ in chunk {
+                    let exists = reader.read_u8()? != 0;
+                    let hash = read_hash(reader)?;
+                    hashes.push(exists.then_some(hash));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(hashes)
+    }
+
+    fn batch_size(&mut self) -> Result<usize> {
+        let limit = self
+            .server_hello()?
+            .limits
+            .iter()
+            .find(|(id, _)| *id == Limit::MaxBatchPaths as u8)
+            .map_or(MAX_BATCH_PATHS as u64, |(_, value)| *value);
+        Ok(limit.clamp(1, MAX_BATCH_PATHS as u64) as usize)
+    }
+}
+
+/// A negotiated connection.
+struct Session {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    hello: ServerHello,
+}
+
+impl Session {
+    fn open(address: SocketAddr, options: &ClientOptions) -> Result<Self> {
+        let stream = match options.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout)?,
+            None => TcpStream::connect(address)?,
+        };
+        stream.set_read_timeout(options.timeout)?;
+        stream.set_write_timeout(options.timeout)?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream.try_clone()?);
+
+        protocol::write_client_hello(&mut writer, PROTOCOL_VERSION)?;
+        writer.flush()?;
+        // A server at its connection limit sends a busy error frame instead of a hello.
+        if reader.fill_buf()?.first() == Some(&STATUS_ERROR) {
+            reader.consume(1);
+            return Err(ServerError::read(&mut reader)?.into());
+        }
+        let hello = ServerHello::read(&mut reader)?;
+        match (hello.status, hello.challenge) {
+            (HandshakeStatus::Accepted, _) => {}
+            (HandshakeStatus::UnsupportedVersion, _) => {
+                return Err(ClientError::UnsupportedVersion(hello.version))
+            }
+            (HandshakeStatus::AuthRequired, Some(challenge)) => {
+                let token = options
+                    .token
+                    .as_deref()
+                    .ok_or(ClientError::AuthenticationRequired)?;
+                writer.write_all(&auth::respond(token, &challenge))?;
+                writer.flush()?;
+                read_status(&mut reader)?;
+            }
+            (HandshakeStatus::AuthRequired, None) => {
+                return Err(ClientError::Protocol(
+                    "Authentication required without a challenge".to_string(),
+                ))
+            }
+        }
+
+        Ok(Self {
+            stream,
+            reader,
+            writer,
+            hello,
+        })
+    }
+
+    /// Sends everything buffered for the request and returns the reader for its response.
+    fn response(&mut self) -> Result<&mut BufReader<TcpStream>> {
+        self.writer.flush()?;
+        Ok(&mut self.reader)
+    }
+
+    /// Whether the server closed the connection since the last response, for example
+    /// after its idle timeout. Bytes nobody asked for mean the session is out of step
+    /// and can't be trusted either.
+    fn is_stale(&self) -> bool {
+        if !self.reader.buffer().is_empty() || self.stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let idle = matches!(
+            self.stream.peek(&mut [0u8]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock
+        );
+        self.stream.set_nonblocking(false).is_err() || !idle
+    }
+}
+
+/// Reads the status byte of a response, decoding the error frame that follows a failure.
+fn read_status<R: Read>(reader: &mut R) -> Result<()> {
+    match reader.read_u8()? {
+        STATUS_OK => Ok(()),
+        STATUS_ERROR => Err(ServerError::read(reader)?.into()),
+        status => Err(ClientError::Protocol(format!(
+            "Unknown response status {}",
+            status
+        ))),
+    }
+}
+
+fn read_count<R: Read>(reader: &mut R, expected: usize) -> Result<()> {
+    let count = reader.read_u32()?;
+    if count as usize != expected {
+        return Err(ClientError::Protocol(format!(
+            "Asked for {} records and got {}",
+            expected, count
+        )));
+    }
+    Ok(())
+}
+
+fn read_hash<R: Read>(reader: &mut R) -> Result<FileHash> {
+    let mut hash = FileHash::default();
+    reader.read_exact(&mut hash)?;
+    Ok(hash)
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    match line.strip_suffix('\n') {
+        Some(line) => Ok(line.to_string()),
+        None => Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+/// Copies exactly `length` bytes, failing if `reader` ends early.
+fn copy_exact<R: Read + ?Sized, W: Write + ?Sized>(
+    reader: &mut R,
+    writer: &mut W,
+    length: u64,
+) -> Result<()> {
+    let copied = io::copy(&mut reader.take(length), writer)?;
+    if copied != length {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("Expected {} bytes and got {}", length, copied),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Checks that `value` fits in a request line. The server would refuse it anyway, but a
+/// line break would split the request and leave the session out of step.
+fn checked_line(value: &str, max_length: usize) -> Result<String> {
+    if value.chars().any(char::is_control) {
+        return Err(ClientError::InvalidArgument(format!(
+            "{:?} contains control characters",
+            value
+        )));
+    }
+    if value.len() > max_length {
+        return Err(ClientError::InvalidArgument(format!(
+            "{:?} is longer than {} bytes",
+            value, max_length
+        )));
+    }
+    Ok(value.to_string())
+}
+
+fn line(path: &str) -> Result<String> {
+    checked_line(path, MAX_PATH_LENGTH)
+}
+
+fn glob_line(glob: &str) -> Result<String> {
+    checked_line(glob, MAX_GLOB_LENGTH)
+}
+
+fn lines<S: AsRef<str>>(paths: &[S]) -> Result<Vec<String>> {
+    paths.iter().map(|path| line(path.as_ref())).collect()
+}
+
+struct HashingReader<'a, R: ?Sized> {
+    inner: &'a mut R,
+    digest: Sha256,
+}
+
+impl<R: Read + ?Sized> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.digest.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestServer;
+    use cobalt_fs_server::config::{AuthConfig, LimitsConfig, ServerConfig, TimeoutsConfig};
+    use std::net::TcpListener;
+
+    fn sha256(contents: &[u8]) -> FileHash {
+        Sha256::digest(contents).into()
+    }
+
+    #[test]
+    fn reads_files_over_one_session() {
+        let server = TestServer::start(ServerConfig::default());
+        let mut client = server.connect();
+
+        assert!(client.exists("xml/Person.xml").unwrap());
+        assert!(!client.exists("xml/Missing.xml").unwrap());
+        assert_eq!(client.read("xml/Person.xml").unwrap(), b"<Person/>");
+        assert_eq!(
+            client.read_range("xml/Person.xml", 1, 6).unwrap(),
+            b"Person"
+        );
+
+        let mut listed = client.list("xml", "").unwrap();
+        listed.sort();
+        assert_eq!(listed, ["xml/God.xml", "xml/Person.xml"]);
+        assert_eq!(client.list("xml", "God*").unwrap(), ["xml/God.xml"]);
+        let entries = client.list_dir("").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            (entries[0].name.as_str(), entries[0].stat.kind),
+            ("xml", EntryKind::Directory)
+        );
+        let names: Vec<_> = client
+            .list_dir("xml")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.stat.size))
+            .collect();
+        assert_eq!(
+            names,
+            [("God.xml".to_string(), 6), ("Person.xml".to_string(), 9)]
+        );
+
+        let stat = client.stat("xml/Person.xml").unwrap();
+        assert_eq!((stat.kind, stat.size), (EntryKind::File, 9));
+        let stats = client.stat_batch(&["xml", "xml/Missing.xml"]).unwrap();
+        assert_eq!(stats[0].kind, EntryKind::Directory);
+        assert_eq!(stats[1], FileStat::MISSING);
+
+        let hash = client.hash("xml/Person.xml").unwrap();
+        assert_eq!(hash, sha256(b"<Person/>"));
+        assert_eq!(
+            client
+                .hash_batch(&["xml/Person.xml", "xml/Missing.xml"])
+                .unwrap(),
+            [Some(hash), None]
+        );
+        assert_eq!(
+            client.read_if_changed("xml/Person.xml", &hash).unwrap(),
+            None
+        );
+        assert_eq!(
+            client
+                .read_if_changed("xml/Person.xml", &FileHash::default())
+                .unwrap(),
+            Some((hash, b"<Person/>".to_vec()))
+        );
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn writes_and_manages_files_in_the_mod_root() {
+        let server = TestServer::start(ServerConfig::default());
+        let mut client = server.connect();
+        let mods = server.mods.path();
+
+        let hash = client.write("xml/Person.xml", b"<Modded/>").unwrap();
+        assert_eq!(hash, sha256(b"<Modded/>"));
+        assert_eq!(
+            std::fs::read(mods.join("xml/Person.xml")).unwrap(),
+            b"<Modded/>"
+        );
+        let streamed = [7u8; 100_000];
+        client
+            .write_from("big.bin", &mut &streamed[..], streamed.len() as u64)
+            .unwrap();
+        assert_eq!(std::fs::read(mods.join("big.bin")).unwrap(), streamed);
+
+        client.make_directory("patches/new").unwrap();
+        client
+            .rename("xml/Person.xml", "patches/Person.xml")
+            .unwrap();
+        assert!(mods.join("patches/Person.xml").is_file());
+        client.delete_file("patches/Person.xml").unwrap();
+        client.delete_directory("patches", true).unwrap();
+        assert!(!mods.join("patches").exists());
+        // The data root is untouched.
+        assert_eq!(client.read("xml/Person.xml").unwrap(), b"<Person/>");
+    }
+
+    #[test]
+    fn server_errors_are_typed_and_keep_the_session() {
+        let server = TestServer::start(ServerConfig::default());
+        let mut client = server.connect();
+
+        let err = client.read("xml/Missing.xml").unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotFound));
+        let err = client.read("xml").unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::WrongKind));
+        let err = client.read("../outside").unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::InvalidPath));
+        let err = client.delete_file("missing").unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::NotFound));
+        assert!(matches!(
+            client.read("xml\nPerson.xml"),
+            Err(ClientError::InvalidArgument(_))
+        ));
+        assert_eq!(client.read("xml/God.xml").unwrap(), b"<God/>");
+    }
+
+    #[test]
+    fn authenticates_with_the_token() {
+        let server = TestServer::start(ServerConfig {
+            auth: AuthConfig {
+                token: "secret".to_string(),
+            },
+            ..ServerConfig::default()
+        });
+        let options = |token: Option<&str>| ClientOptions {
+            token: token.map(str::to_string),
+            ..ClientOptions::default()
+        };
+
+        let mut client = Client::connect(server.address, options(Some("secret"))).unwrap();
+        assert_eq!(client.read("xml/God.xml").unwrap(), b"<God/>");
+        assert!(matches!(
+            Client::connect(server.address, options(None)),
+            Err(ClientError::AuthenticationRequired)
+        ));
+        let err = Client::connect(server.address, options(Some("guess")))
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), Some(ErrorCode::AuthenticationFailed));
+    }
+
+    #[test]
+    fn full_servers_answer_with_a_busy_error() {
+        let server = TestServer::start(ServerConfig {
+            limits: LimitsConfig {
+                workers: 1,
+                max_connections: 1,
+            },
+            ..ServerConfig::default()
+        });
+        let _session = server.connect();
+
+        let err = Client::connect(server.address, ClientOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(err, ClientError::Server(_)), "{}", err);
+        assert_eq!(err.code(), Some(ErrorCode::Busy));
+    }
+
+    #[test]
+    fn reconnects_after_the_server_closes_an_idle_session() {
+        let server = TestServer::start(ServerConfig {
+            timeouts: TimeoutsConfig {
+                idle_secs: 1,
+                ..TimeoutsConfig::default()
+            },
+            ..ServerConfig::default()
+        });
+        let mut client = server.connect();
+        assert!(client.exists("xml/God.xml").unwrap());
+        std::thread::sleep(Duration::from_millis(1500));
+        assert!(client.exists("xml/God.xml").unwrap());
+        std::fs::remove_file(server.data.path().join("xml/God.xml")).unwrap();
+        assert!(!client.exists("xml/God.xml").unwrap());
+    }
+
+    #[test]
+    fn silent_servers_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = ClientOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..ClientOptions::default()
+        };
+        let err = Client::connect(listener.local_addr().unwrap(), options)
+            .err()
+            .unwrap();
+        assert!(err.is_timeout(), "{}", err);
+    }
+}
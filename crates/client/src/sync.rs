@@ -0,0 +1,413 @@
+//! Mirrors a local folder into a directory of the server's mod root. Sizes are compared
+//! first and only files of equal size are hashed, so planning a sync of an unchanged
+//! folder transfers no file contents.
+
+use crate::{Client, ClientError, EntryKind, ErrorCode, FileHash, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// The file doesn't exist on the server.
+    New,
+    /// The file exists on the server with different contents.
+    Modified,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncAction {
+    Upload {
+        local: PathBuf,
+        /// Path relative to the mod root.
+        remote: String,
+        size: u64,
+        change: Change,
+    },
+    /// Removes a remote file with no local counterpart. Only planned when deletion was
+    /// asked for.
+    Delete { remote: String },
+    /// Removes a remote directory that the planned deletions leave without files.
+    DeleteDirectory { remote: String },
+}
+
+impl SyncAction {
+    pub fn remote(&self) -> &str {
+        match self {
+            SyncAction::Upload { remote, .. }
+            | SyncAction::Delete { remote }
+            | SyncAction::DeleteDirectory { remote } => remote,
+        }
+    }
+}
+
+/// What a sync would change: uploads and file deletions ordered by remote path, followed
+/// by directory deletions, deepest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub actions: Vec<SyncAction>,
+    /// Remote paths of files that are already identical.
+    pub unchanged: Vec<String>,
+}
+
+impl SyncPlan {
+    /// Whether the remote directory already mirrors the local folder.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+/// Compares every file below `local` with the files below `remote` in the mod root. With
+/// `delete`, remote files missing locally are planned for deletion, along with the
+/// directories below `remote` that are left without files. A remote directory that
+/// doesn't exist yet counts as empty.
+///
+/// A path that is a file on one side and a directory on the other can't be synced and
+/// fails the plan with [`ClientError::InvalidArgument`], before anything is transferred.
+pub fn plan(client: &mut Client, local: &Path, remote: &str, delete: bool) -> Result<SyncPlan> {
+    let remote = normalize(remote)?;
+    let local_files = local_files(local)?
+        .into_iter()
+        .map(|(relative, file)| (join(&remote, &relative), file))
+        .collect::<BTreeMap<_, _>>();
+    let remote_files = client
+        .mod_list(&remote, "")?
+        .into_iter()
+        .collect::<BTreeSet<_>>();
+
+    for path in local_files.keys() {
+        if let Some(file) = parents(path).find(|dir| remote_files.contains(*dir)) {
+            return Err(conflict(
+                file,
+                "a file on the server but a directory locally",
+            ));
+        }
+    }
+
+    let paths = local_files.keys().collect::<Vec<_>>();
+    let stats = client.mod_stat_batch(&paths)?;
+    let mut actions = BTreeMap::new();
+    let mut same_size = Vec::new();
+    for (path, stat) in paths.into_iter().zip(stats) {
+        let (local, size) = &local_files[path];
+        let change = match stat.kind {
+            EntryKind::Directory => {
+                return Err(conflict(
+                    path,
+                    "a directory on the server but a file locally",
+                ))
+            }
+            EntryKind::File if stat.size == *size => {
+                same_size.push(path.as_str());
+                continue;
+            }
+            EntryKind::File => Change::Modified,
+            EntryKind::Missing => Change::New,
+        };
+        actions.insert(path.as_str(), upload(local, path, *size, change));
+    }
+
+    let hashes = client.mod_hash_batch(&same_size)?;
+    let mut unchanged = Vec::new();
+    for (path, hash) in same_size.into_iter().zip(hashes) {
+        let (local, size) = &local_files[path];
+        if hash == Some(hash_file(local)?) {
+            unchanged.push(path.to_string());
+        } else {
+            actions.insert(path, upload(local, path, *size, Change::Modified));
+        }
+    }
+
+    let mut emptied = BTreeSet::new();
+    if delete {
+        for path in &remote_files {
+            if !local_files.contains_key(path) {
+                actions.insert(
+                    path.as_str(),
+                    SyncAction::Delete {
+                        remote: path.clone(),
+                    },
+                );
+                emptied.extend(parents(path).filter(|dir| dir.len() > remote.len()));
+            }
+        }
+    }
+    // Every remote file that stays has a local counterpart, so a directory is left
+    // without files when no local file is below it.
+    let mut emptied = emptied
+        .into_iter()
+        .filter(|dir| {
+            let prefix = format!("{}/", dir);
+            local_files
+                .range(prefix.clone()..)
+                .next()
+                .is_none_or(|(path, _)| !path.starts_with(&prefix))
+        })
+        .collect::<Vec<_>>();
+    emptied.sort_by_key(|dir| std::cmp::Reverse(dir.matches('/').count()));
+
+    let mut actions = actions.into_values().collect::<Vec<_>>();
+    actions.extend(emptied.into_iter().map(|dir| SyncAction::DeleteDirectory {
+        remote: dir.to_string(),
+    }));
+    Ok(SyncPlan { actions, unchanged })
+}
+
+/// Carries out a plan, stopping at the first failure. `done` is called after each
+/// action completes. A directory that still holds entries the listing doesn't show, such
+/// as empty subdirectories, is left in place.
+pub fn apply(
+    client: &mut Client,
+    plan: &SyncPlan,
+    mut done: impl FnMut(&SyncAction),
+) -> Result<()> {
+    for action in &plan.actions {
+        match action {
+            SyncAction::Upload {
+                local,
+                remote,
+                size,
+                ..
+            } => {
+                let file = File::open(local)?;
+                client.write_from(remote, &mut BufReader::new(file), *size)?;
+            }
+            SyncAction::Delete { remote } => client.delete_file(remote)?,
+            SyncAction::DeleteDirectory { remote } => {
+                match client.delete_directory(remote, false) {
+                    Err(err) if err.code() == Some(ErrorCode::DirectoryNotEmpty) => {}
+                    result => result?,
+                }
+            }
+        }
+        done(action);
+    }
+    Ok(())
+}
+
+fn conflict(path: &str, problem: &str) -> ClientError {
+    ClientError::InvalidArgument(format!("Cannot sync {}, it is {}", path, problem))
+}
+
+fn upload(local: &Path, remote: &str, size: u64, change: Change) -> SyncAction {
+    SyncAction::Upload {
+        local: local.to_path_buf(),
+        remote: remote.to_string(),
+        size,
+        change,
+    }
+}
+
+/// Collects the files below `root` with their sizes, keyed by their `/`-separated path
+/// relative to `root`. Symlinks are skipped, so a link back up the tree can't make the
+/// walk loop.
+fn local_files(root: &Path) -> Result<BTreeMap<String, (PathBuf, u64)>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| {
+                    ClientError::InvalidArgument(format!("{} is not valid UTF-8", path.display()))
+                })?;
+            let relative = join(&prefix, name);
+            let metadata = std::fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                dirs.push((path, relative));
+            } else if metadata.is_file() {
+                files.insert(relative, (path, metadata.len()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn hash_file(path: &Path) -> Result<FileHash> {
+    let mut digest = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut digest)?;
+    Ok(digest.finalize().into())
+}
+
+/// The remote directory as the server lists it: `/`-separated without empty or `.`
+/// components. `..` is refused, since the server would resolve it against a different
+/// directory than the listing is compared with.
+pub fn normalize(dir: &str) -> Result<String> {
+    let components = dir
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect::<Vec<_>>();
+    if components.contains(&"..") {
+        return Err(ClientError::InvalidArgument(format!(
+            "{} may not contain '..'",
+            dir
+        )));
+    }
+    Ok(components.join("/"))
+}
+
+/// The directories containing `path`, innermost first.
+fn parents(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(path), |path| path.rfind('/').map(|end| &path[..end])).skip(1)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TestServer;
+    use cobalt_fs_server::config::ServerConfig;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn summary(plan: &SyncPlan) -> Vec<(&str, Option<Change>)> {
+        plan.actions
+            .iter()
+            .map(|action| match action {
+                SyncAction::Upload { change, .. } => (action.remote(), Some(*change)),
+                SyncAction::Delete { .. } | SyncAction::DeleteDirectory { .. } => {
+                    (action.remote(), None)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_transfers_only_differences() {
+        let server = TestServer::start(ServerConfig::default());
+        let mods = server.mods.path();
+        write(mods, "xml/same.xml", "same");
+        write(mods, "xml/size.xml", "old");
+        write(mods, "xml/content.xml", "aaa");
+        write(mods, "xml/extra.xml", "x");
+        write(mods, "other/keep.xml", "keep");
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "same.xml", "same");
+        write(local.path(), "size.xml", "newer");
+        write(local.path(), "content.xml", "bbb");
+        write(local.path(), "sub/new.xml", "new");
+        let mut client = server.connect();
+
+        let plan = plan(&mut client, local.path(), "xml", false).unwrap();
+        assert_eq!(
+            summary(&plan),
+            [
+                ("xml/content.xml", Some(Change::Modified)),
+                ("xml/size.xml", Some(Change::Modified)),
+                ("xml/sub/new.xml", Some(Change::New)),
+            ]
+        );
+        assert_eq!(plan.unchanged, ["xml/same.xml"]);
+
+        let plan = super::plan(&mut client, local.path(), "/xml/", true).unwrap();
+        assert_eq!(summary(&plan)[1], ("xml/extra.xml", None));
+        let mut done = Vec::new();
+        apply(&mut client, &plan, |action| done.push(action.clone())).unwrap();
+        assert_eq!(done, plan.actions);
+
+        assert_eq!(std::fs::read(mods.join("xml/content.xml")).unwrap(), b"bbb");
+        assert_eq!(std::fs::read(mods.join("xml/sub/new.xml")).unwrap(), b"new");
+        assert!(!mods.join("xml/extra.xml").exists());
+        assert!(mods.join("other/keep.xml").exists());
+        assert!(super::plan(&mut client, local.path(), "xml", true)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn missing_remote_directories_count_as_empty() {
+        let server = TestServer::start(ServerConfig::default());
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "a.xml", "a");
+        let mut client = server.connect();
+
+        let plan = plan(&mut client, local.path(), "fresh", true).unwrap();
+        assert_eq!(summary(&plan), [("fresh/a.xml", Some(Change::New))]);
+    }
+
+    #[test]
+    fn deletions_remove_directories_left_empty() {
+        let server = TestServer::start(ServerConfig::default());
+        let mods = server.mods.path();
+        write(mods, "xml/keep/a.xml", "a");
+        write(mods, "xml/keep/gone.xml", "gone");
+        write(mods, "xml/old/deeper/b.xml", "b");
+        write(mods, "xml/old/c.xml", "c");
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "keep/a.xml", "a");
+        let mut client = server.connect();
+
+        let plan = plan(&mut client, local.path(), "xml", true).unwrap();
+        assert_eq!(
+            plan.actions[3..],
+            [
+                SyncAction::DeleteDirectory {
+                    remote: "xml/old/deeper".to_string()
+                },
+                SyncAction::DeleteDirectory {
+                    remote: "xml/old".to_string()
+                },
+            ]
+        );
+        apply(&mut client, &plan, |_| {}).unwrap();
+        assert!(!mods.join("xml/old").exists());
+        assert!(!mods.join("xml/keep/gone.xml").exists());
+        assert!(mods.join("xml/keep/a.xml").exists());
+    }
+
+    #[test]
+    fn files_and_directories_at_one_path_conflict() {
+        let server = TestServer::start(ServerConfig::default());
+        let mods = server.mods.path();
+        write(mods, "xml/dir/a.xml", "a");
+        write(mods, "xml/file", "remote");
+        let mut client = server.connect();
+
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "dir", "local");
+        write(local.path(), "new.xml", "new");
+        let err = plan(&mut client, local.path(), "xml", false).unwrap_err();
+        assert!(matches!(err, ClientError::InvalidArgument(_)), "{}", err);
+
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "file/b.xml", "b");
+        let err = plan(&mut client, local.path(), "xml", false).unwrap_err();
+        assert!(matches!(err, ClientError::InvalidArgument(_)), "{}", err);
+        assert!(!mods.join("xml/new.xml").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_skipped() {
+        let local = tempfile::tempdir().unwrap();
+        write(local.path(), "sub/a.xml", "a");
+        std::os::unix::fs::symlink(local.path(), local.path().join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink("a.xml", local.path().join("sub/link.xml")).unwrap();
+        let files = local_files(local.path()).unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["sub/a.xml"]);
+    }
+
+    #[test]
+    fn remote_directories_may_not_climb_out() {
+        assert_eq!(normalize("./xml//sub/").unwrap(), "xml/sub");
+        assert_eq!(normalize("\\xml\\").unwrap(), "xml");
+        assert!(matches!(
+            normalize("xml/../.."),
+            Err(ClientError::InvalidArgument(_))
+        ));
+    }
+}
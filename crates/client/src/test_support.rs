@@ -0,0 +1,59 @@
+//! A server on a loopback port for the tests of this crate and of the tools built on it.
+//! Built for this crate's own tests and with the `test-support` feature.
+
+use crate::{Client, ClientOptions};
+use cobalt_fs_server::config::{LogConfig, ServerConfig};
+use cobalt_fs_server::fs::HostFs;
+use cobalt_fs_server::logger::Logger;
+use cobalt_fs_server::server;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+/// Serves a data root and a mod root from temporary directories. The data root starts
+/// with `xml/Person.xml` and `xml/God.xml`, the mod root empty.
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub data: TempDir,
+    pub mods: TempDir,
+}
+
+impl TestServer {
+    /// Starts serving with `config`, whose roots and log file are replaced.
+    pub fn start(config: ServerConfig) -> Self {
+        let data = tempfile::tempdir().unwrap();
+        let mods = tempfile::tempdir().unwrap();
+        std::fs::create_dir(data.path().join("xml")).unwrap();
+        std::fs::write(data.path().join("xml/Person.xml"), b"<Person/>").unwrap();
+        std::fs::write(data.path().join("xml/God.xml"), b"<God/>").unwrap();
+
+        let config = ServerConfig {
+            data_root: path_string(data.path()),
+            mod_root: path_string(mods.path()),
+            log: LogConfig {
+                file: String::new(),
+                append: false,
+            },
+            ..config
+        };
+        let fs = HostFs::new([data.path(), mods.path()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let logger = Arc::new(Logger::new(&config.log));
+        std::thread::spawn(move || server::serve(listener, Arc::new(config), Arc::new(fs), logger));
+        Self {
+            address,
+            data,
+            mods,
+        }
+    }
+
+    pub fn connect(&self) -> Client {
+        Client::connect(self.address, ClientOptions::default()).unwrap()
+    }
+}
+
+fn path_string(path: &Path) -> String {
+    path.canonicalize().unwrap().to_str().unwrap().to_string()
+}
@@ -0,0 +1,128 @@
+//! Serves an extracted romfs from a desktop machine over the same protocol as the Switch
+//! plugin, for developing clients and integration testing without a console.
+
+use anyhow::{bail, Context, Result};
+use cobalt_fs_server::config::ServerConfig;
+use cobalt_fs_server::fs::HostFs;
+use cobalt_fs_server::logger::Logger;
+use cobalt_fs_server::server;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage: cobalt-fs-host <ROMFS> [OPTIONS]
+
+Serves the Data directory of an extracted romfs, or ROMFS itself if it has no Data
+directory.
+
+Options:
+  --mod-root <DIR>   Directory uploads are written to. Writes are refused without it or a
+                     mod_root in the config.
+  --config <FILE>    Server config file in the same format as on the SD card.
+  --bind <ADDRESS>   Interface to listen on.
+  --port <PORT>      Port to listen on.
+  --token <TOKEN>    Require clients to authenticate with this token.";
+
+struct Args {
+    romfs: PathBuf,
+    mod_root: Option<PathBuf>,
+    config: Option<PathBuf>,
+    bind: Option<String>,
+    port: Option<u16>,
+    token: Option<String>,
+}
+
+impl Args {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self> {
+        let mut romfs = None;
+        let mut parsed = Args {
+            romfs: PathBuf::new(),
+            mod_root: None,
+            config: None,
+            bind: None,
+            port: None,
+            token: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--mod-root" => parsed.mod_root = Some(value()?.into()),
+                "--config" => parsed.config = Some(value()?.into()),
+                "--bind" => parsed.bind = Some(value()?),
+                "--port" => parsed.port = Some(value()?.parse().context("Invalid port")?),
+                "--token" => parsed.token = Some(value()?),
+                "-h" | "--help" => bail!("{}", USAGE),
+                _ if arg.starts_with('-') => bail!("Unknown option {}\n\n{}", arg, USAGE),
+                _ if romfs.is_none() => romfs = Some(PathBuf::from(arg)),
+                _ => bail!("Unexpected argument {}\n\n{}", arg, USAGE),
+            }
+        }
+        parsed.romfs = romfs.with_context(|| format!("Missing ROMFS\n\n{}", USAGE))?;
+        Ok(parsed)
+    }
+
+    fn config(&self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => {
+                let mut config = ServerConfig::default();
+                config.log.file = String::new();
+                config
+            }
+        };
+        config.data_root = path_string(&data_root(&self.romfs)?)?;
+        if let Some(mod_root) = &self.mod_root {
+            std::fs::create_dir_all(mod_root)
+                .with_context(|| format!("Could not create {}", mod_root.display()))?;
+            config.mod_root = path_string(mod_root)?;
+        }
+        if let Some(bind) = &self.bind {
+            config.bind = bind.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(token) = &self.token {
+            config.auth.token = token.clone();
+        }
+        Ok(config)
+    }
+}
+
+/// Clients address files relative to `rom:/Data`, so an extracted romfs is served from its
+/// Data directory.
+fn data_root(romfs: &Path) -> Result<PathBuf> {
+    if !romfs.is_dir() {
+        bail!("{} is not a directory", romfs.display());
+    }
+    let data = romfs.join("Data");
+    Ok(if data.is_dir() {
+        data
+    } else {
+        romfs.to_path_buf()
+    })
+}
+
+fn path_string(path: &Path) -> Result<String> {
+    path.canonicalize()?
+        .to_str()
+        .map(str::to_string)
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    let config = args.config()?;
+
+    let mut roots = vec![config.data_root.clone()];
+    if !config.mod_root.is_empty() {
+        roots.push(config.mod_root.clone());
+    }
+    let fs = HostFs::new(&roots).context("Could not open the served directories")?;
+    let logger = Arc::new(Logger::new(&config.log));
+    server::run_server(config, Arc::new(fs), logger);
+    bail!("The server stopped")
+}
@@ -0,0 +1,295 @@
+//! Decides which peers may connect, before anything is read from their stream. Peers must
+//! fall inside one of the allowed CIDR ranges, may open only so many connections per
+//! window, and are banned for a while after repeated protocol errors.
+
+use crate::config::AccessConfig;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Peers tracked before idle entries are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// An address range such as `192.168.0.0/16`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Self> {
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid address in range '{}'", text))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .with_context(|| format!("Invalid prefix length in range '{}'", text))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            bail!("Prefix length {} is too long in range '{}'", prefix, text);
+        }
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // Clients reaching a dual-stack socket over IPv4 show up as mapped IPv6 addresses.
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            IpAddr::V4(_) => address,
+        };
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    NotAllowed,
+    RateLimited,
+    /// Banned for protocol errors, with the time left on the ban.
+    Banned(Duration),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::NotAllowed => write!(f, "address is outside the allowed ranges"),
+            Denied::RateLimited => write!(f, "too many connections"),
+            Denied::Banned(left) => {
+                write!(f, "banned for protocol errors, {}s left", left.as_secs())
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Peer {
+    connections: VecDeque<Instant>,
+    errors: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Peer {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.connections.is_empty()
+            && self.errors.is_empty()
+            && !matches!(self.banned_until, Some(until) if until > now)
+    }
+}
+
+/// Drops the instants that fell out of the window ending at `now`.
+fn expire(instants: &mut VecDeque<Instant>, window: Duration, now: Instant) {
+    while instants
+        .front()
+        .is_some_and(|&instant| now.duration_since(instant) >= window)
+    {
+        instants.pop_front();
+    }
+}
+
+pub struct AccessControl {
+    allowed: Vec<Cidr>,
+    max_connections: usize,
+    max_errors: usize,
+    window: Duration,
+    ban: Duration,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessConfig) -> Result<Self> {
+        Ok(Self {
+            allowed: config
+                .allowed
+                .iter()
+                .map(|range| Cidr::parse(range))
+                .collect::<Result<_>>()?,
+            max_connections: config.max_connections_per_ip,
+            max_errors: config.max_protocol_errors,
+            window: Duration::from_secs(config.window_secs),
+            ban: Duration::from_secs(config.ban_secs),
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the address is inside the allowed ranges.
+    pub fn allows(&self, address: IpAddr) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(address))
+    }
+
+    /// Checks a newly accepted peer and counts the connection against its rate limit.
+    pub fn admit(&self, address: IpAddr, now: Instant) -> Result<(), Denied> {
+        if !self.allows(address) {
+            return Err(Denied::NotAllowed);
+        }
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        if peers.len() >= PRUNE_THRESHOLD {
+            peers.retain(|_, peer| {
+                expire(&mut peer.connections, self.window, now);
+                expire(&mut peer.errors, self.window, now);
+                !peer.is_idle(now)
+            });
+        }
+        let peer = peers.entry(address).or_default();
+        if let Some(until) = peer.banned_until {
+            if until > now {
+                return Err(Denied::Banned(until - now));
+            }
+            peer.banned_until = None;
+        }
+        if self.max_connections > 0 {
+            expire(&mut peer.connections, self.window, now);
+            if peer.connections.len() >= self.max_connections {
+                return Err(Denied::RateLimited);
+            }
+            peer.connections.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Counts a protocol error against the peer and bans it once it reaches the limit
+    /// within one window. Returns whether the peer was banned.
+    pub fn record_protocol_error(&self, address: IpAddr, now: Instant) -> bool {
+        if self.max_errors == 0 {
+            return false;
+        }
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        let peer = peers.entry(address).or_default();
+        expire(&mut peer.errors, self.window, now);
+        peer.errors.push_back(now);
+        if peer.errors.len() < self.max_errors {
+            return false;
+        }
+        peer.errors.clear();
+        peer.banned_until = Some(now + self.ban);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn access(allowed: &[&str], max_connections: usize, max_errors: usize) -> AccessControl {
+        AccessControl::new(&AccessConfig {
+            allowed: allowed.iter().map(|range| range.to_string()).collect(),
+            max_connections_per_ip: max_connections,
+            max_protocol_errors: max_errors,
+            window_secs: 60,
+            ban_secs: 300,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges_match_their_prefix() {
+        let lan = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains(ip("192.168.1.42")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+        assert!(!lan.contains(ip("fe80::1")));
+
+        assert!(Cidr::parse("10.0.0.5").unwrap().contains(ip("10.0.0.5")));
+        assert!(!Cidr::parse("10.0.0.5").unwrap().contains(ip("10.0.0.6")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Cidr::parse("fe80::/10").unwrap().contains(ip("fe80::1234")));
+        assert!(!Cidr::parse("fe80::/10")
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for range in [
+            "",
+            "192.168.1.0/33",
+            "::/129",
+            "192.168.1/24",
+            "a.b.c.d",
+            "1.2.3.4/x",
+        ] {
+            assert!(Cidr::parse(range).is_err(), "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn only_allowed_ranges_are_admitted() {
+        let now = Instant::now();
+        let open = access(&[], 0, 0);
+        assert_eq!(open.admit(ip("203.0.113.9"), now), Ok(()));
+
+        let lan = access(&["192.168.0.0/16", "127.0.0.1"], 0, 0);
+        assert_eq!(lan.admit(ip("192.168.4.2"), now), Ok(()));
+        assert_eq!(lan.admit(ip("127.0.0.1"), now), Ok(()));
+        assert_eq!(lan.admit(ip("203.0.113.9"), now), Err(Denied::NotAllowed));
+    }
+
+    #[test]
+    fn connections_are_rate_limited_per_ip() {
+        let access = access(&[], 2, 0);
+        let start = Instant::now();
+        assert_eq!(access.admit(ip("10.0.0.1"), start), Ok(()));
+        assert_eq!(access.admit(ip("10.0.0.1"), start), Ok(()));
+        assert_eq!(
+            access.admit(ip("10.0.0.1"), start),
+            Err(Denied::RateLimited)
+        );
+        assert_eq!(access.admit(ip("10.0.0.2"), start), Ok(()));
+        assert_eq!(
+            access.admit(ip("10.0.0.1"), start + Duration::from_secs(60)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn repeated_protocol_errors_ban_the_peer() {
+        let access = access(&[], 0, 3);
+        let start = Instant::now();
+        let peer = ip("10.0.0.1");
+        assert!(!access.record_protocol_error(peer, start));
+        assert!(!access.record_protocol_error(peer, start));
+        assert!(access.record_protocol_error(peer, start));
+        assert_eq!(
+            access.admit(peer, start + Duration::from_secs(100)),
+            Err(Denied::Banned(Duration::from_secs(200)))
+        );
+        assert_eq!(access.admit(ip("10.0.0.2"), start), Ok(()));
+        assert_eq!(access.admit(peer, start + Duration::from_secs(300)), Ok(()));
+    }
+
+    #[test]
+    fn errors_outside_the_window_are_forgotten() {
+        let access = access(&[], 0, 2);
+        let start = Instant::now();
+        let peer = ip("10.0.0.1");
+        assert!(!access.record_protocol_error(peer, start));
+        assert!(!access.record_protocol_error(peer, start + Duration::from_secs(61)));
+        assert_eq!(access.admit(peer, start + Duration::from_secs(62)), Ok(()));
+    }
+}
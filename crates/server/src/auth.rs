@@ -0,0 +1,81 @@
+//! Challenge-response authentication with a pre-shared token. The server sends a fresh
+//! random challenge and the client proves it knows the token by answering with
+//! HMAC-SHA256(token, challenge), so the token itself never crosses the network.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type Challenge = [u8; 32];
+pub type AuthResponse = [u8; 32];
+
+/// Creates a challenge that is never repeated, so a recorded response can't be replayed.
+///
+/// There is no OS random source on every target, so the challenge hashes together a
+/// process-wide counter, the current time and the randomly keyed std hasher.
+pub fn new_challenge() -> Challenge {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+    let mut digest = Sha256::new();
+    digest.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    digest.update(nanos.to_be_bytes());
+    digest.update(hasher.finish().to_be_bytes());
+    digest.finalize().into()
+}
+
+/// The answer a client holding `token` sends for `challenge`.
+pub fn respond(token: &str, challenge: &Challenge) -> AuthResponse {
+    mac(token, challenge).finalize().into_bytes().into()
+}
+
+/// Checks a client's answer in constant time.
+pub fn verify(token: &str, challenge: &Challenge, response: &AuthResponse) -> bool {
+    mac(token, challenge).verify_slice(response).is_ok()
+}
+
+fn mac(token: &str, challenge: &Challenge) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(challenge);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_right_token_passes() {
+        let challenge = new_challenge();
+        let response = respond("secret", &challenge);
+        assert!(verify("secret", &challenge, &response));
+        assert!(!verify("Secret", &challenge, &response));
+        assert!(!verify("secret", &new_challenge(), &response));
+    }
+
+    #[test]
+    fn response_is_hmac_sha256() {
+        let mut challenge = [0u8; 32];
+        challenge[..28].copy_from_slice(b"what do ya want for nothing?");
+        let expected = "733e03185f9a9dcc4df0c2beeeacb82f0db51073b56011b0654070290b5f8d43";
+        let response: String = respond("Jefe", &challenge)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn challenges_are_not_repeated() {
+        let challenges: std::collections::HashSet<Challenge> =
+            (0..1000).map(|_| new_challenge()).collect();
+        assert_eq!(challenges.len(), 1000);
+    }
+}
@@ -0,0 +1,472 @@
+//! Server settings, read from a TOML file on the SD card at startup. Every key is
+//! optional and defaults to the behavior the server had before it was configurable,
+//! except `mod_root`: writes stay disabled until a mod root is configured.
+//!
+//! `data_root` and `mod_root` each name one directory. Lists are not accepted, so uploads
+//! for several mods go to the same mod root.
+//!
+//! ```toml
+//! bind = "0.0.0.0"
+//! port = 7878
+//! fallback_ports = []
+//! data_root = "rom:/Data"
+//! mod_root = ""
+//!
+//! [bind_retry]
+//! attempts = 10
+//! initial_delay_ms = 500
+//! max_delay_ms = 8000
+//!
+//! [discovery]
+//! enabled = true
+//! port = 7879
+//! nickname = "Nintendo Switch"
+//! title_id = 0x0100a6301214e000
+//!
+//! [auth]
+//! token = ""
+//!
+//! [access]
+//! allowed = []
+//! max_connections_per_ip = 0
+//! max_protocol_errors = 5
+//! window_secs = 60
+//! ban_secs = 300
+//!
+//! [timeouts]
+//! read_secs = 30
+//! write_secs = 30
+//! idle_secs = 300
+//!
+//! [limits]
+//! workers = 4
+//! max_connections = 16
+//!
+//! [log]
+//! file = "sd:/engage/mods/astra-cobalt-plugin/log.txt"
+//! append = false
+//! ```
+
+use crate::access::Cidr;
+use crate::protocol::TITLE_ID;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+pub const CONFIG_PATH: &str = "sd:/engage/mods/astra-cobalt-plugin/config.toml";
+
+/// Longest console nickname, in bytes, so beacons stay well inside one datagram.
+const MAX_NICKNAME_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Interface the server listens on.
+    pub bind: String,
+    pub port: u16,
+    /// Ports tried in order when `port` is taken.
+    pub fallback_ports: Vec<u16>,
+    pub bind_retry: BindRetryConfig,
+    /// Root that read requests are served from. A single directory, not a list.
+    pub data_root: String,
+    /// Directory uploaded files are written to, mirroring the layout of the data root.
+    /// Empty disables every write request. Like `data_root` it is a single directory, so
+    /// only one mod can be written to per config.
+    pub mod_root: String,
+    pub discovery: DiscoveryConfig,
+    pub auth: AuthConfig,
+    pub access: AccessConfig,
+    pub timeouts: TimeoutsConfig,
+    pub limits: LimitsConfig,
+    pub log: LogConfig,
+}
+
+/// How long to keep trying to bind when every port fails, for example because the network
+/// is not up yet at boot. The delay between rounds doubles up to `max_delay_ms`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindRetryConfig {
+    /// Rounds over all ports before giving up.
+    pub attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Answer discovery probes so clients can find the console without its IP address.
+    pub enabled: bool,
+    /// UDP port probes are received on.
+    pub port: u16,
+    /// Name clients show for this console.
+    pub nickname: String,
+    /// Title id advertised in beacons, for builds of the plugin loaded into another game.
+    pub title_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Pre-shared token clients must prove they know. Empty lets anyone connect.
+    pub token: String,
+}
+
+impl AuthConfig {
+    pub fn enabled(&self) -> bool {
+        !self.token.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// CIDR ranges such as `192.168.0.0/16` that may connect. Empty allows every address.
+    pub allowed: Vec<String>,
+    /// Connections one address may open per window. 0 disables the limit.
+    pub max_connections_per_ip: usize,
+    /// Protocol errors within one window that get an address banned. 0 disables bans.
+    pub max_protocol_errors: usize,
+    pub window_secs: u64,
+    pub ban_secs: u64,
+}
+
+/// Socket timeouts in seconds, where 0 waits forever.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// Longest wait for the next bytes of a request that has started.
+    pub read_secs: u64,
+    /// Longest wait for the client to accept more of a response.
+    pub write_secs: u64,
+    /// Longest wait for the next request of a negotiated session.
+    pub idle_secs: u64,
+}
+
+impl TimeoutsConfig {
+    pub fn read(&self) -> Option<Duration> {
+        seconds(self.read_secs)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        seconds(self.write_secs)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        seconds(self.idle_secs)
+    }
+}
+
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Number of worker threads kept running to serve connections.
+    pub workers: usize,
+    /// Connections served at once. While every worker is busy, further connections get a
+    /// thread of their own until they close, so this and not `workers` bounds the threads
+    /// serving connections. Connections past this limit are answered with a busy error and
+    /// closed.
+    pub max_connections: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log file path. Empty disables the log file, leaving only stdout.
+    pub file: String,
+    /// Keep the previous log instead of starting a new one at each launch.
+    pub append: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0".to_string(),
+            port: 7878,
+            fallback_ports: Vec::new(),
+            bind_retry: BindRetryConfig::default(),
+            data_root: "rom:/Data".to_string(),
+            mod_root: String::new(),
+            discovery: DiscoveryConfig::default(),
+            auth: AuthConfig::default(),
+            access: AccessConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for BindRetryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 10,
+            initial_delay_ms: 500,
+            max_delay_ms: 8000,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            port: 7879,
+            nickname: "Nintendo Switch".to_string(),
+            title_id: TITLE_ID,
+        }
+    }
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        Self {
+            allowed: Vec::new(),
+            max_connections_per_ip: 0,
+            max_protocol_errors: 5,
+            window_secs: 60,
+            ban_secs: 300,
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            read_secs: 30,
+            write_secs: 30,
+            idle_secs: 300,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_connections: 16,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            file: "sd:/engage/mods/astra-cobalt-plugin/log.txt".to_string(),
+            append: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the config file at `path`. A missing file gives the defaults.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)
+                .with_context(|| format!("Invalid config file {}", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("Could not read {}", path.display())),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.bind_retry.attempts == 0 {
+            bail!("bind_retry.attempts must be at least 1");
+        }
+        if self.bind_retry.initial_delay_ms > self.bind_retry.max_delay_ms {
+            bail!(
+                "bind_retry.initial_delay_ms ({}) must not exceed bind_retry.max_delay_ms ({})",
+                self.bind_retry.initial_delay_ms,
+                self.bind_retry.max_delay_ms
+            );
+        }
+        if self.limits.workers == 0 {
+            bail!("limits.workers must be at least 1");
+        }
+        if self.limits.max_connections < self.limits.workers {
+            bail!(
+                "limits.max_connections ({}) must be at least limits.workers ({})",
+                self.limits.max_connections,
+                self.limits.workers
+            );
+        }
+        if self.discovery.nickname.len() > MAX_NICKNAME_LENGTH {
+            bail!(
+                "discovery.nickname is longer than {} bytes",
+                MAX_NICKNAME_LENGTH
+            );
+        }
+        for range in &self.access.allowed {
+            Cidr::parse(range).context("Invalid range in access.allowed")?;
+        }
+        if self.mod_root.starts_with("rom:") {
+            bail!(
+                "mod_root {} is on the read-only rom:/ filesystem",
+                self.mod_root
+            );
+        }
+        Ok(())
+    }
+
+    /// The port followed by the fallback ports, without duplicates.
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports = vec![self.port];
+        for &port in &self.fallback_ports {
+            if !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        ports
+    }
+
+    /// Settings that are valid but probably unintended, for the log at startup.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if !self.mod_root.is_empty() && !self.auth.enabled() {
+            warnings.push(format!(
+                "Writes to {} are enabled without an auth token, anyone who can reach the \
+                 server may change or delete mod files. Set auth.token to require one.",
+                self.mod_root
+            ));
+        }
+        warnings
+    }
+
+    /// The effective settings in the config file format, for the log. The auth token is
+    /// left out.
+    pub fn report(&self) -> String {
+        let mut config = self.clone();
+        if config.auth.enabled() {
+            config.auth.token = "<hidden>".to_string();
+        }
+        toml::to_string(&config).unwrap_or_else(|err| format!("{:?}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        let config = ServerConfig::parse("").unwrap();
+        assert_eq!(config, ServerConfig::default());
+        assert_eq!(config.ports(), [7878]);
+        assert_eq!(config.data_root, "rom:/Data");
+        assert_eq!(config.mod_root, "");
+    }
+
+    #[test]
+    fn missing_file_gives_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::load(dir.path().join("config.toml")).unwrap();
+        assert_eq!(config, ServerConfig::default());
+    }
+
+    #[test]
+    fn partial_file_overrides_only_given_keys() {
+        let config = ServerConfig::parse(
+            r#"
+            port = 9000
+            mod_root = "sd:/engage/mods/my-mod/Data"
+
+            [discovery]
+            title_id = 0x01006a800016e000
+
+            [limits]
+            workers = 2
+
+            [log]
+            file = ""
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.ports(), [9000]);
+        assert_eq!(config.mod_root, "sd:/engage/mods/my-mod/Data");
+        assert_eq!(config.discovery.title_id, 0x0100_6a80_0016_e000);
+        assert!(config.discovery.enabled);
+        assert_eq!(config.limits.workers, 2);
+        assert_eq!(config.limits.max_connections, 16);
+        assert_eq!(config.log.file, "");
+        assert!(!config.log.append);
+    }
+
+    #[test]
+    fn unknown_keys_and_bad_values_are_rejected() {
+        assert!(ServerConfig::parse("prot = 9000").is_err());
+        assert!(ServerConfig::parse("port = 70000").is_err());
+        assert!(ServerConfig::parse("[limits]\nworkers = 0").is_err());
+        assert!(ServerConfig::parse("[limits]\nworkers = 8\nmax_connections = 4").is_err());
+        assert!(ServerConfig::parse("mod_root = \"rom:/Data\"").is_err());
+        assert!(ServerConfig::parse("[bind_retry]\nattempts = 0").is_err());
+        assert!(ServerConfig::parse("[bind_retry]\ninitial_delay_ms = 9000").is_err());
+        assert!(ServerConfig::parse("[access]\nallowed = [\"192.168.0.0/40\"]").is_err());
+        assert!(
+            ServerConfig::parse(&format!("[discovery]\nnickname = \"{}\"", "a".repeat(65)))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn zero_timeouts_wait_forever() {
+        let config = ServerConfig::parse("[timeouts]\nread_secs = 0\nidle_secs = 5").unwrap();
+        assert_eq!(config.timeouts.read(), None);
+        assert_eq!(config.timeouts.write(), Some(Duration::from_secs(30)));
+        assert_eq!(config.timeouts.idle(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn fallback_ports_follow_the_port() {
+        let config =
+            ServerConfig::parse("port = 9000\nfallback_ports = [9001, 9000, 9002, 9001]").unwrap();
+        assert_eq!(config.ports(), [9000, 9001, 9002]);
+    }
+
+    #[test]
+    fn report_hides_the_auth_token() {
+        let config = ServerConfig::parse("[auth]\ntoken = \"hunter2\"").unwrap();
+        assert!(config.auth.enabled());
+        assert!(!config.report().contains("hunter2"));
+        assert!(!ServerConfig::default().auth.enabled());
+    }
+
+    #[test]
+    fn writes_without_a_token_are_warned_about() {
+        let config = ServerConfig::parse("mod_root = \"sd:/mods\"").unwrap();
+        assert_eq!(config.warnings().len(), 1);
+        let config =
+            ServerConfig::parse("mod_root = \"sd:/mods\"\n[auth]\ntoken = \"hunter2\"").unwrap();
+        assert!(config.warnings().is_empty());
+        assert!(ServerConfig::parse("mod_root = \"\"")
+            .unwrap()
+            .warnings()
+            .is_empty());
+    }
+
+    #[test]
+    fn report_round_trips() {
+        let config = ServerConfig {
+            port: 1234,
+            fallback_ports: vec![1235, 1236],
+            log: LogConfig {
+                append: true,
+                ..LogConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        assert_eq!(ServerConfig::parse(&config.report()).unwrap(), config);
+    }
+}
@@ -0,0 +1,182 @@
+//! Answers UDP discovery probes so clients can list the consoles on the local network
+//! without knowing their addresses. See the protocol docs for the probe and beacon layout.
+
+use crate::access::AccessControl;
+use crate::config::ServerConfig;
+use crate::logger::Logger;
+use crate::protocol::{Beacon, DISCOVERY_MAGIC};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Delay after the first failed receive or send, doubled for each failure in a row.
+const ERROR_DELAY: Duration = Duration::from_millis(100);
+const MAX_ERROR_DELAY: Duration = Duration::from_secs(5);
+/// Failures in a row after which the socket is assumed broken and discovery stops.
+const MAX_ERRORS: u32 = 10;
+
+/// Starts answering probes in the background, advertising the file server on `port`.
+/// Probes from addresses outside the allowed ranges are ignored, like their connections.
+pub fn spawn(config: &ServerConfig, port: u16, logger: Arc<Logger>) {
+    let discovery = &config.discovery;
+    if !discovery.enabled {
+        logger.log("Discovery is disabled");
+        return;
+    }
+    let access = match AccessControl::new(&config.access) {
+        Ok(access) => access,
+        Err(err) => {
+            logger.log(&format!(
+                "Invalid access settings, discovery is off: {:?}",
+                err
+            ));
+            return;
+        }
+    };
+    let address = format!("{}:{}", config.bind, discovery.port);
+    let socket = match UdpSocket::bind(&address) {
+        Ok(socket) => socket,
+        Err(err) => {
+            logger.log(&format!(
+                "Could not bind discovery socket {}, clients will need the console's address: {}",
+                address, err
+            ));
+            return;
+        }
+    };
+    let mut beacon = Vec::new();
+    Beacon::new(port, discovery.title_id, &discovery.nickname)
+        .write(&mut beacon)
+        .unwrap();
+    logger.log(&format!(
+        "Answering discovery probes on {} as '{}'",
+        address, discovery.nickname
+    ));
+
+    let thread_logger = logger.clone();
+    let result = std::thread::Builder::new()
+        .name("discovery".to_string())
+        .spawn(move || {
+            let mut errors = 0;
+            loop {
+                match answer_probe(&socket, &beacon, &access) {
+                    Ok(Some(client)) => {
+                        errors = 0;
+                        thread_logger.log(&format!("Answered discovery probe from {}", client));
+                    }
+                    Ok(None) => errors = 0,
+                    Err(err) => {
+                        thread_logger.log_error(&err);
+                        errors += 1;
+                        if errors >= MAX_ERRORS {
+                            thread_logger.log(&format!(
+                                "Discovery failed {} times in a row, clients will need the console's address",
+                                errors
+                            ));
+                            return;
+                        }
+                        std::thread::sleep(error_delay(errors));
+                    }
+                }
+            }
+        });
+    if let Err(err) = result {
+        logger.log(&format!("Could not start discovery thread: {:?}", err));
+    }
+}
+
+/// How long to wait after `errors` failures in a row, so a socket that keeps failing does
+/// not spin.
+fn error_delay(errors: u32) -> Duration {
+    ERROR_DELAY
+        .saturating_mul(1 << (errors.max(1) - 1).min(16))
+        .min(MAX_ERROR_DELAY)
+}
+
+/// Waits for one datagram and answers it with `beacon` if it is a probe from an allowed
+/// address. Returns the address that was answered.
+fn answer_probe(
+    socket: &UdpSocket,
+    beacon: &[u8],
+    access: &AccessControl,
+) -> io::Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 64];
+    let (length, client) = socket.recv_from(&mut buf)?;
+    if !buf[..length].starts_with(DISCOVERY_MAGIC) || !access.allows(client.ip()) {
+        return Ok(None);
+    }
+    socket.send_to(beacon, client)?;
+    Ok(Some(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AccessConfig;
+    use crate::protocol::TITLE_ID;
+    use std::io::Cursor;
+
+    fn access(allowed: &[&str]) -> AccessControl {
+        AccessControl::new(&AccessConfig {
+            allowed: allowed.iter().map(|range| range.to_string()).collect(),
+            ..AccessConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn probes_are_answered_with_a_beacon() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut beacon = Vec::new();
+        Beacon::new(7878, TITLE_ID, "Test")
+            .write(&mut beacon)
+            .unwrap();
+
+        client
+            .send_to(b"nope", server.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(answer_probe(&server, &beacon, &access(&[])).unwrap(), None);
+
+        client
+            .send_to(DISCOVERY_MAGIC, server.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(
+            answer_probe(&server, &beacon, &access(&["127.0.0.0/8"])).unwrap(),
+            Some(client.local_addr().unwrap())
+        );
+
+        let mut buf = [0u8; 64];
+        let (length, _) = client.recv_from(&mut buf).unwrap();
+        let received = Beacon::read(&mut Cursor::new(&buf[..length])).unwrap();
+        assert_eq!(received.port, 7878);
+        assert_eq!(received.title_id, TITLE_ID);
+        assert_eq!(received.nickname, "Test");
+    }
+
+    #[test]
+    fn probes_from_outside_the_allowed_ranges_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        client
+            .send_to(DISCOVERY_MAGIC, server.local_addr().unwrap())
+            .unwrap();
+        let access = access(&["192.168.0.0/16"]);
+        assert_eq!(answer_probe(&server, b"beacon", &access).unwrap(), None);
+        assert!(client.recv_from(&mut [0u8; 64]).is_err());
+    }
+
+    #[test]
+    fn failures_in_a_row_back_off() {
+        assert_eq!(error_delay(1), ERROR_DELAY);
+        assert_eq!(error_delay(2), ERROR_DELAY * 2);
+        assert_eq!(error_delay(3), ERROR_DELAY * 4);
+        assert_eq!(error_delay(MAX_ERRORS), MAX_ERROR_DELAY);
+        assert_eq!(error_delay(u32::MAX), MAX_ERROR_DELAY);
+    }
+}
@@ -0,0 +1,304 @@
+//! Errors as clients see them: a numeric code they can match on, a human readable message
+//! and optional context such as the path involved.
+
+use crate::mod_fs::ModFsError;
+use crate::paths::PathError;
+use crate::protocol::{ReadExt, WriteExt, STATUS_ERROR};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    NotFound = 1,
+    PermissionDenied = 2,
+    /// The path fails validation, for example by leaving its root.
+    InvalidPath = 3,
+    UnsupportedOperation = 4,
+    /// A count or length exceeds one of the server's limits.
+    TooLarge = 5,
+    Busy = 6,
+    /// Anything the server can't classify. Also used for codes from a newer server.
+    Internal = 7,
+    /// The request is malformed or its arguments make no sense.
+    InvalidRequest = 8,
+    AlreadyExists = 9,
+    DirectoryNotEmpty = 10,
+    /// A file was expected where there is a directory, or the other way around.
+    WrongKind = 11,
+    AuthenticationFailed = 12,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 12] = [
+        ErrorCode::NotFound,
+        ErrorCode::PermissionDenied,
+        ErrorCode::InvalidPath,
+        ErrorCode::UnsupportedOperation,
+        ErrorCode::TooLarge,
+        ErrorCode::Busy,
+        ErrorCode::Internal,
+        ErrorCode::InvalidRequest,
+        ErrorCode::AlreadyExists,
+        ErrorCode::DirectoryNotEmpty,
+        ErrorCode::WrongKind,
+        ErrorCode::AuthenticationFailed,
+    ];
+
+    /// Decodes a code, treating unknown ones as [`ErrorCode::Internal`].
+    pub fn from_u16(value: u16) -> Self {
+        ErrorCode::ALL
+            .into_iter()
+            .find(|code| *code as u16 == value)
+            .unwrap_or(ErrorCode::Internal)
+    }
+
+    fn from_io(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::UnexpectedEof => {
+                ErrorCode::InvalidRequest
+            }
+            ErrorKind::Unsupported => ErrorCode::UnsupportedOperation,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    fn from_mod_fs(err: &ModFsError) -> Self {
+        match err {
+            ModFsError::NoModRoot | ModFsError::ReadOnlyRoot(_) | ModFsError::ModRoot => {
+                ErrorCode::PermissionDenied
+            }
+            ModFsError::InvalidPath(_) => ErrorCode::InvalidPath,
+            ModFsError::NotFound(_) => ErrorCode::NotFound,
+            ModFsError::NotAFile(_) | ModFsError::NotADirectory(_) => ErrorCode::WrongKind,
+            ModFsError::DirectoryNotEmpty(_) => ErrorCode::DirectoryNotEmpty,
+            ModFsError::AlreadyExists(_) => ErrorCode::AlreadyExists,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub context: Option<String>,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            context: None,
+        }
+    }
+
+    pub fn with_context(mut self, context: impl Into<String>) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    /// Classifies an error by the first cause in its chain with a known type. The causes
+    /// wrapped around it become the context. Errors without a known cause are internal.
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        let chain: Vec<&(dyn std::error::Error + 'static)> = err.chain().collect();
+        for (index, cause) in chain.iter().enumerate() {
+            let code = if let Some(err) = cause.downcast_ref::<ServerError>() {
+                let mut err = err.clone();
+                if index > 0 && err.context.is_none() {
+                    err.context = Some(join(&chain[..index]));
+                }
+                return err;
+            } else if let Some(err) = cause.downcast_ref::<ModFsError>() {
+                ErrorCode::from_mod_fs(err)
+            } else if cause.is::<PathError>() {
+                ErrorCode::InvalidPath
+            } else if let Some(err) = cause.downcast_ref::<io::Error>() {
+                ErrorCode::from_io(err.kind())
+            } else if cause.is::<glob::PatternError>() {
+                ErrorCode::InvalidRequest
+            } else {
+                continue;
+            };
+            let err = Self::new(code, cause.to_string());
+            return match index {
+                0 => err,
+                _ => err.with_context(join(&chain[..index])),
+            };
+        }
+        let err = Self::new(ErrorCode::Internal, chain[0].to_string());
+        match chain.len() {
+            1 => err,
+            _ => err.with_context(join(&chain[1..])),
+        }
+    }
+
+    /// Writes the error frame, status byte included.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(STATUS_ERROR)?;
+        write_string(writer, &self.message)?;
+        writer.write_u16(self.code as u16)?;
+        write_string(writer, self.context.as_deref().unwrap_or(""))
+    }
+
+    /// Writes the version 1 error frame, which holds the message alone.
+    pub fn write_message_only<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(STATUS_ERROR)?;
+        write_string(writer, &self.message)
+    }
+
+    /// Reads the rest of an error frame after its status byte.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let message = read_string(reader)?;
+        let code = ErrorCode::from_u16(reader.read_u16()?);
+        let context = read_string(reader)?;
+        Ok(Self {
+            code,
+            message,
+            context: (!context.is_empty()).then_some(context),
+        })
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)?;
+        if let Some(context) = &self.context {
+            write!(f, " ({})", context)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+fn join(causes: &[&(dyn std::error::Error + 'static)]) -> String {
+    causes
+        .iter()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    writer.write_u64(value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+/// Longest message or context accepted when decoding, so a corrupt length can't make the
+/// reader allocate without bound.
+const MAX_STRING_LENGTH: u64 = 64 * 1024;
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let length = reader.read_u64()?;
+    if length > MAX_STRING_LENGTH {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Error string of {} bytes is too long", length),
+        ));
+    }
+    let mut buf = vec![0u8; length as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn frame_round_trip() {
+        for err in [
+            ServerError::new(ErrorCode::NotFound, "No such file"),
+            ServerError::new(ErrorCode::Busy, "Busy").with_context("16 connections"),
+        ] {
+            let mut buf = Vec::new();
+            err.write(&mut buf).unwrap();
+            assert_eq!(buf[0], STATUS_ERROR);
+            assert_eq!(ServerError::read(&mut Cursor::new(&buf[1..])).unwrap(), err);
+        }
+    }
+
+    #[test]
+    fn frame_starts_with_the_legacy_layout() {
+        let mut buf = Vec::new();
+        ServerError::new(ErrorCode::NotFound, "oops")
+            .write(&mut buf)
+            .unwrap();
+        assert_eq!(
+            buf,
+            [1, 0, 0, 0, 0, 0, 0, 0, 4, b'o', b'o', b'p', b's', 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn version_1_frames_hold_only_the_message() {
+        let mut buf = Vec::new();
+        ServerError::new(ErrorCode::NotFound, "oops")
+            .with_context("xml/a.xml")
+            .write_message_only(&mut buf)
+            .unwrap();
+        assert_eq!(buf, [1, 0, 0, 0, 0, 0, 0, 0, 4, b'o', b'o', b'p', b's']);
+    }
+
+    #[test]
+    fn unknown_codes_are_internal() {
+        assert_eq!(ErrorCode::from_u16(0), ErrorCode::Internal);
+        assert_eq!(ErrorCode::from_u16(999), ErrorCode::Internal);
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::from_u16(code as u16), code);
+        }
+    }
+
+    #[test]
+    fn io_errors_are_classified_by_kind() {
+        let err = anyhow::Error::from(io::Error::from(ErrorKind::NotFound))
+            .context("rom:/Data/xml/Missing.xml");
+        let classified = ServerError::from_anyhow(&err);
+        assert_eq!(classified.code, ErrorCode::NotFound);
+        assert_eq!(
+            classified.context.as_deref(),
+            Some("rom:/Data/xml/Missing.xml")
+        );
+
+        let err = anyhow::Error::from(io::Error::from(ErrorKind::PermissionDenied));
+        let classified = ServerError::from_anyhow(&err);
+        assert_eq!(classified.code, ErrorCode::PermissionDenied);
+        assert_eq!(classified.context, None);
+    }
+
+    #[test]
+    fn typed_errors_are_classified() {
+        let path = anyhow::Error::from(PathError::EscapesRoot("..".to_string()));
+        assert_eq!(ServerError::from_anyhow(&path).code, ErrorCode::InvalidPath);
+
+        let mod_fs = anyhow::Error::from(ModFsError::DirectoryNotEmpty(PathBuf::from("a")));
+        assert_eq!(
+            ServerError::from_anyhow(&mod_fs).code,
+            ErrorCode::DirectoryNotEmpty
+        );
+
+        let server = Err::<(), _>(ServerError::new(ErrorCode::TooLarge, "Too many"))
+            .context("StatBatch")
+            .unwrap_err();
+        let classified = ServerError::from_anyhow(&server);
+        assert_eq!(classified.code, ErrorCode::TooLarge);
+        assert_eq!(classified.message, "Too many");
+        assert_eq!(classified.context.as_deref(), Some("StatBatch"));
+    }
+
+    #[test]
+    fn unclassified_errors_are_internal() {
+        let err = anyhow::anyhow!("Something broke").context("While reading");
+        let classified = ServerError::from_anyhow(&err);
+        assert_eq!(classified.code, ErrorCode::Internal);
+        assert_eq!(classified.message, "While reading");
+        assert_eq!(classified.context.as_deref(), Some("Something broke"));
+    }
+}
@@ -0,0 +1,305 @@
+//! Filesystem access used by the server. Request handling only goes through
+//! [`FileSystem`], so the same server runs against the Switch's `rom:/` and `sd:/` mounts
+//! or against plain directories on a desktop host.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// A file opened for reading.
+pub trait ReadFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadFile for T {}
+
+/// A file opened for writing.
+pub trait WriteFile: Write + Send {
+    /// Flushes the contents to storage, before the file is moved into place.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl WriteFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Size in bytes, zero for directories.
+    pub len: u64,
+    /// Not every filesystem records modification times.
+    pub modified: Option<SystemTime>,
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        Self {
+            is_dir: metadata.is_dir(),
+            len: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+/// The filesystem operations the server needs. Paths are already resolved against the
+/// configured roots.
+pub trait FileSystem: Send + Sync {
+    /// Metadata of the entry at `path`, following symlinks. Missing entries fail with
+    /// [`ErrorKind::NotFound`].
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    /// Creates or truncates the file at `path`.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Moves `from` to `to`, replacing a file already at `to`.
+    fn replace(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+}
+
+/// The Switch's filesystem through Skyline's std, with paths on `rom:/` and `sd:/`.
+pub struct SkylineFs;
+
+impl FileSystem for SkylineFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        Ok(std::fs::metadata(path)?.into())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        read_dir(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    /// The SD card refuses to rename onto an existing file, so the old file is removed
+    /// first when that happens.
+    fn replace(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match std::fs::rename(from, to) {
+            Err(_) if to.is_file() => {
+                std::fs::remove_file(to)?;
+                std::fs::rename(from, to)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Plain directories on a desktop host, for example an extracted romfs. Every path must
+/// lie inside one of the roots after following symlinks, so a link can't expose the rest
+/// of the host's files.
+pub struct HostFs {
+    roots: Vec<PathBuf>,
+}
+
+impl HostFs {
+    /// The roots must exist.
+    pub fn new<I, P>(roots: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let roots = roots
+            .into_iter()
+            .map(|root| root.as_ref().canonicalize())
+            .collect::<io::Result<_>>()?;
+        Ok(Self { roots })
+    }
+
+    /// Fails unless `path`, or the nearest ancestor of it that exists, resolves to a
+    /// location inside one of the roots.
+    fn confine(&self, path: &Path) -> io::Result<()> {
+        let existing = path
+            .ancestors()
+            .find(|ancestor| ancestor.exists())
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        let resolved = existing.canonicalize()?;
+        if self.roots.iter().any(|root| resolved.starts_with(root)) {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{} is outside of the served directories", path.display()),
+            ))
+        }
+    }
+}
+
+impl FileSystem for HostFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.confine(path)?;
+        Ok(std::fs::metadata(path)?.into())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        self.confine(path)?;
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        self.confine(path)?;
+        read_dir(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        self.confine(path)?;
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.confine(path)?;
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.confine(path)?;
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.confine(path)?;
+        std::fs::remove_dir(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.confine(path)?;
+        std::fs::remove_dir_all(path)
+    }
+
+    fn replace(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.confine(from)?;
+        self.confine(to)?;
+        std::fs::rename(from, to)
+    }
+}
+
+fn read_dir(path: &Path) -> io::Result<Vec<DirEntry>> {
+    std::fs::read_dir(path)?
+        .map(|entry| {
+            let path = entry?.path();
+            Ok(DirEntry {
+                is_dir: path.is_dir(),
+                path,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_fs_serves_files_inside_its_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs = HostFs::new([dir.path()]).unwrap();
+        let file = dir.path().join("xml/a.xml");
+
+        fs.create_dir_all(file.parent().unwrap()).unwrap();
+        fs.create(&file).unwrap().write_all(b"abc").unwrap();
+        let mut contents = String::new();
+        fs.open(&file)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "abc");
+        assert_eq!(fs.metadata(&file).unwrap().len, 3);
+        assert!(fs.metadata(&file.with_file_name("b.xml")).is_err());
+        assert_eq!(
+            fs.read_dir(dir.path()).unwrap(),
+            [DirEntry {
+                path: dir.path().join("xml"),
+                is_dir: true
+            }]
+        );
+    }
+
+    #[test]
+    fn host_fs_refuses_paths_outside_its_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"").unwrap();
+        let fs = HostFs::new([root.path()]).unwrap();
+
+        let err = fs.open(&outside.path().join("secret")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let err = fs.create(&outside.path().join("new")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(!fs.exists(&root.path().join("../").join(outside.path())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn host_fs_does_not_follow_symlinks_out_of_its_roots() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("link")).unwrap();
+        let fs = HostFs::new([root.path()]).unwrap();
+
+        let err = fs.open(&root.path().join("link/secret")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        let err = fs.create(&root.path().join("link/new")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn replace_overwrites_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.tmp");
+        let to = dir.path().join("a");
+        for fs in [
+            Box::new(SkylineFs) as Box<dyn FileSystem>,
+            Box::new(HostFs::new([dir.path()]).unwrap()),
+        ] {
+            std::fs::write(&from, b"new").unwrap();
+            std::fs::write(&to, b"old").unwrap();
+            fs.replace(&from, &to).unwrap();
+            assert_eq!(std::fs::read(&to).unwrap(), b"new");
+            assert!(!from.exists());
+        }
+    }
+}
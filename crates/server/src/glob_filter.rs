@@ -0,0 +1,129 @@
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Filter built from the glob line of a list request.
+///
+/// The line holds comma-separated patterns. Patterns starting with `!` exclude paths and
+/// the rest include them; a path is kept if it matches any include (or there are none) and
+/// no exclude. `*` stops at `/` while `**` crosses directories. A pattern without a `/`
+/// is matched against the file name alone, so `*.bundle` finds bundles at any depth while
+/// `xml/*.xml.bundle` only looks directly inside `xml`.
+#[derive(Debug, Default)]
+pub struct GlobFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl GlobFilter {
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut filter = GlobFilter::default();
+        for pattern in line.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (target, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (&mut filter.exclude, pattern),
+                None => (&mut filter.include, pattern),
+            };
+            let pattern = pattern.replace('\\', "/");
+            target.push(
+                Pattern::new(&pattern)
+                    .with_context(|| format!("Invalid glob pattern '{}'", pattern))?,
+            );
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Tests a path relative to the listed directory.
+    pub fn matches(&self, path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| Self::matches_one(p, path)))
+            && !self.exclude.iter().any(|p| Self::matches_one(p, path))
+    }
+
+    fn matches_one(pattern: &Pattern, path: &Path) -> bool {
+        if pattern.as_str().contains('/') {
+            pattern.matches_path_with(path, MATCH_OPTIONS)
+        } else {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| pattern.matches_with(name, MATCH_OPTIONS))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, path: &str) -> bool {
+        GlobFilter::parse(filter).unwrap().matches(Path::new(path))
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(GlobFilter::parse(" , ").unwrap().is_empty());
+        assert!(matches("", "xml/Person.xml.bundle"));
+    }
+
+    #[test]
+    fn pattern_without_separator_matches_file_name_at_any_depth() {
+        assert!(matches("*.bundle", "Person.bundle"));
+        assert!(matches("*.bundle", "xml/deep/Person.bundle"));
+        assert!(!matches("*.bundle", "xml/Person.xml"));
+    }
+
+    #[test]
+    fn single_star_does_not_cross_directories() {
+        assert!(matches("xml/*.xml.bundle", "xml/Person.xml.bundle"));
+        assert!(!matches("xml/*.xml.bundle", "xml/sub/Person.xml.bundle"));
+        assert!(!matches("xml/*.xml.bundle", "other/Person.xml.bundle"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("xml/**/*.bundle", "xml/a/b/Person.bundle"));
+        assert!(matches("**/message/*", "a/message/en.txt"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("chapter[0-9].bundle", "chapter3.bundle"));
+        assert!(!matches("chapter[0-9].bundle", "chapterA.bundle"));
+        assert!(matches("[!a]*.txt", "b.txt"));
+    }
+
+    #[test]
+    fn multiple_patterns_and_exclusions() {
+        let filter = "*.xml, *.bundle, !test_*, !debug/**";
+        assert!(matches(filter, "xml/Person.xml"));
+        assert!(matches(filter, "asset.bundle"));
+        assert!(!matches(filter, "xml/test_Person.xml"));
+        assert!(!matches(filter, "debug/asset.bundle"));
+        assert!(!matches(filter, "readme.txt"));
+    }
+
+    #[test]
+    fn exclusions_alone_keep_everything_else() {
+        assert!(matches("!*.tmp", "a.xml"));
+        assert!(!matches("!*.tmp", "a.tmp"));
+    }
+
+    #[test]
+    fn backslashes_are_treated_as_separators() {
+        assert!(matches("xml\\*.xml", "xml/Person.xml"));
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        let err = GlobFilter::parse("[abc").unwrap_err();
+        assert!(err.to_string().contains("[abc"));
+    }
+}
@@ -0,0 +1,16 @@
+//! The Astra file server: its wire protocol, configuration and request handling, with
+//! filesystem access behind [`fs::FileSystem`] so it can run on the Switch or on a desktop
+//! host.
+
+mod access;
+pub mod auth;
+pub mod config;
+mod discovery;
+pub mod error;
+pub mod fs;
+mod glob_filter;
+pub mod logger;
+mod mod_fs;
+mod paths;
+pub mod protocol;
+pub mod server;
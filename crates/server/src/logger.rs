@@ -0,0 +1,55 @@
+use crate::config::LogConfig;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::{Mutex, PoisonError};
+
+/// Logs to stdout and the log file on the SD card. Shared between worker threads, so
+/// writes to the file are serialized and each line is tagged with the thread that wrote it.
+pub struct Logger {
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    pub fn new(config: &LogConfig) -> Self {
+        if config.file.is_empty() {
+            return Self { file: None };
+        }
+        println!("Attempting to create log file...");
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(config.append)
+            .truncate(!config.append)
+            .open(&config.file);
+        Self {
+            file: match file {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(err) => {
+                    println!("Error creating log file: {:?}", err);
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn log(&self, message: &str) {
+        let message = match std::thread::current().name() {
+            Some(name) => format!("[{}] {}", name, message),
+            None => message.to_string(),
+        };
+        println!("{}", message);
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut writer = BufWriter::new(&mut *file);
+            let _ = writeln!(writer, "{}", message);
+            let _ = writer.flush();
+        }
+    }
+
+    pub fn log_error<E>(&self, error: E)
+    where
+        E: std::fmt::Debug,
+    {
+        self.log(&format!("ERROR: {:?}", error));
+    }
+}
@@ -0,0 +1,259 @@
+//! Operations that modify the mod root. Every path is resolved against the configured
+//! root first, so nothing outside of it (and never `rom:/`) can be changed.
+
+use crate::fs::FileSystem;
+use crate::paths::{PathError, RequestPath};
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum ModFsError {
+    /// No mod root is configured, so nothing can be written.
+    NoModRoot,
+    /// The configured root is on the read-only game filesystem.
+    ReadOnlyRoot(String),
+    /// The request path fails validation, for example by leaving the root.
+    InvalidPath(PathError),
+    /// The operation would remove or move the root itself.
+    ModRoot,
+    NotFound(PathBuf),
+    NotAFile(PathBuf),
+    NotADirectory(PathBuf),
+    DirectoryNotEmpty(PathBuf),
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for ModFsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModFsError::NoModRoot => write!(f, "No mod root is configured, writes are disabled"),
+            ModFsError::ReadOnlyRoot(root) => {
+                write!(f, "Mod root {} is on the read-only rom:/ filesystem", root)
+            }
+            ModFsError::InvalidPath(err) => write!(f, "Invalid mod path: {}", err),
+            ModFsError::ModRoot => write!(f, "The mod root itself cannot be modified"),
+            ModFsError::NotFound(path) => write!(f, "{} does not exist", path.display()),
+            ModFsError::NotAFile(path) => write!(f, "{} is not a file", path.display()),
+            ModFsError::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            ModFsError::DirectoryNotEmpty(path) => {
+                write!(f, "{} is not empty", path.display())
+            }
+            ModFsError::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for ModFsError {}
+
+/// Validates a request path against `root`, which may not be empty or on `rom:/`.
+pub fn parse(root: &str, path: &str) -> Result<RequestPath, ModFsError> {
+    if root.is_empty() {
+        return Err(ModFsError::NoModRoot);
+    }
+    if root.starts_with("rom:") {
+        return Err(ModFsError::ReadOnlyRoot(root.to_string()));
+    }
+    RequestPath::parse(path).map_err(ModFsError::InvalidPath)
+}
+
+/// Validates a request path and maps it onto `root`. The root itself is allowed, for
+/// requests that only look at the mod root.
+pub fn resolve(root: &str, path: &str) -> Result<PathBuf, ModFsError> {
+    Ok(parse(root, path)?.under(root))
+}
+
+/// Validates a request path and maps it onto `root`, refusing the root itself.
+pub fn resolve_entry(root: &str, path: &str) -> Result<PathBuf, ModFsError> {
+    let path = parse(root, path)?;
+    if path.is_root() {
+        return Err(ModFsError::ModRoot);
+    }
+    Ok(path.under(root))
+}
+
+pub fn delete_file(fs: &dyn FileSystem, path: &Path) -> anyhow::Result<()> {
+    match fs.metadata(path) {
+        Ok(metadata) if metadata.is_dir => Err(ModFsError::NotAFile(path.into()).into()),
+        Ok(_) => Ok(fs.remove_file(path)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Err(ModFsError::NotFound(path.into()).into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Deletes a directory. Unless `recursive` is set the directory must be empty.
+pub fn delete_directory(fs: &dyn FileSystem, path: &Path, recursive: bool) -> anyhow::Result<()> {
+    match fs.metadata(path) {
+        Ok(metadata) if !metadata.is_dir => {
+            return Err(ModFsError::NotADirectory(path.into()).into())
+        }
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(ModFsError::NotFound(path.into()).into())
+        }
+        Err(err) => return Err(err.into()),
+    }
+    if recursive {
+        fs.remove_dir_all(path)?;
+    } else {
+        if !fs.read_dir(path)?.is_empty() {
+            return Err(ModFsError::DirectoryNotEmpty(path.into()).into());
+        }
+        fs.remove_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Moves a file or directory, creating the destination's parents. Existing entries are
+/// never overwritten.
+pub fn rename(fs: &dyn FileSystem, from: &Path, to: &Path) -> anyhow::Result<()> {
+    if !fs.exists(from) {
+        return Err(ModFsError::NotFound(from.into()).into());
+    }
+    if fs.exists(to) {
+        return Err(ModFsError::AlreadyExists(to.into()).into());
+    }
+    if let Some(parent) = to.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    Ok(fs.replace(from, to)?)
+}
+
+/// Creates a directory and its parents. Succeeds if the directory already exists.
+pub fn make_directory(fs: &dyn FileSystem, path: &Path) -> anyhow::Result<()> {
+    if fs.metadata(path).is_ok_and(|metadata| !metadata.is_dir) {
+        return Err(ModFsError::AlreadyExists(path.into()).into());
+    }
+    Ok(fs.create_dir_all(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::SkylineFs;
+
+    fn error(result: anyhow::Result<()>) -> ModFsError {
+        result.unwrap_err().downcast::<ModFsError>().unwrap()
+    }
+
+    #[test]
+    fn resolve_stays_inside_the_root() {
+        let root = "sd:/engage/mods/astra/Data";
+        assert_eq!(
+            resolve_entry(root, "xml\\Person.xml").unwrap(),
+            Path::new("sd:/engage/mods/astra/Data/xml/Person.xml")
+        );
+        assert_eq!(
+            resolve_entry(root, "./xml//").unwrap(),
+            Path::new(root).join("xml")
+        );
+        for path in ["../other", "xml/../../x", "rom:/Data/xml", "xml/sd:/x"] {
+            assert!(matches!(
+                resolve_entry(root, path),
+                Err(ModFsError::InvalidPath(_))
+            ));
+        }
+        assert!(matches!(
+            resolve_entry(root, "/."),
+            Err(ModFsError::ModRoot)
+        ));
+        assert_eq!(resolve(root, "/.").unwrap(), Path::new(root));
+        assert!(matches!(resolve("", "xml"), Err(ModFsError::NoModRoot)));
+    }
+
+    #[test]
+    fn resolve_refuses_missing_and_rom_roots() {
+        assert!(matches!(
+            resolve_entry("", "xml"),
+            Err(ModFsError::NoModRoot)
+        ));
+        assert!(matches!(
+            resolve_entry("rom:/Data", "xml"),
+            Err(ModFsError::ReadOnlyRoot(_))
+        ));
+    }
+
+    #[test]
+    fn delete_file_only_removes_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.xml");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(matches!(
+            error(delete_file(&SkylineFs, dir.path())),
+            ModFsError::NotAFile(_)
+        ));
+        delete_file(&SkylineFs, &file).unwrap();
+        assert!(!file.exists());
+        assert!(matches!(
+            error(delete_file(&SkylineFs, &file)),
+            ModFsError::NotFound(_)
+        ));
+    }
+
+    #[test]
+    fn delete_directory_needs_recursive_flag_for_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("xml");
+        std::fs::create_dir_all(target.join("sub")).unwrap();
+        std::fs::write(target.join("sub/a.xml"), b"").unwrap();
+
+        assert!(matches!(
+            error(delete_directory(
+                &SkylineFs,
+                &target.join("sub/a.xml"),
+                true
+            )),
+            ModFsError::NotADirectory(_)
+        ));
+        assert!(matches!(
+            error(delete_directory(&SkylineFs, &target, false)),
+            ModFsError::DirectoryNotEmpty(_)
+        ));
+        delete_directory(&SkylineFs, &target, true).unwrap();
+        assert!(!target.exists());
+        assert!(matches!(
+            error(delete_directory(&SkylineFs, &target, true)),
+            ModFsError::NotFound(_)
+        ));
+    }
+
+    #[test]
+    fn rename_moves_without_overwriting() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("a.xml");
+        let to = dir.path().join("new/dir/b.xml");
+        std::fs::write(&from, b"a").unwrap();
+
+        rename(&SkylineFs, &from, &to).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"a");
+        assert!(matches!(
+            error(rename(&SkylineFs, &from, &to)),
+            ModFsError::NotFound(_)
+        ));
+
+        std::fs::write(&from, b"b").unwrap();
+        assert!(matches!(
+            error(rename(&SkylineFs, &from, &to)),
+            ModFsError::AlreadyExists(_)
+        ));
+    }
+
+    #[test]
+    fn make_directory_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("a/b");
+        make_directory(&SkylineFs, &target).unwrap();
+        make_directory(&SkylineFs, &target).unwrap();
+        assert!(target.is_dir());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(matches!(
+            error(make_directory(&SkylineFs, &file)),
+            ModFsError::AlreadyExists(_)
+        ));
+    }
+}
@@ -0,0 +1,258 @@
+//! Validation of the paths clients send. Every request path is relative to one of the
+//! server's roots and must stay inside it after normalization.
+
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The path contains a NUL byte, which would truncate it at the filesystem layer.
+    NulByte(String),
+    /// A component names a device or mount such as `rom:`, `sd:` or `C:`.
+    DevicePrefix(String),
+    /// `..` components climb above the root.
+    EscapesRoot(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::NulByte(path) => write!(f, "Path {:?} contains a NUL byte", path),
+            PathError::DevicePrefix(path) => {
+                write!(f, "Path '{}' names a device or mount prefix", path)
+            }
+            PathError::EscapesRoot(path) => write!(f, "Path '{}' escapes its root", path),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// A normalized path relative to a root.
+///
+/// Both `/` and `\` separate components. Empty and `.` components are dropped and `..`
+/// removes the previous component, so the path never contains either. Leading separators
+/// are ignored, meaning `/xml` is the same as `xml`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RequestPath {
+    components: Vec<String>,
+}
+
+impl RequestPath {
+    pub fn parse(raw: &str) -> Result<Self, PathError> {
+        if raw.contains('\0') {
+            return Err(PathError::NulByte(raw.to_string()));
+        }
+        let mut components: Vec<String> = Vec::new();
+        for component in raw.split(['/', '\\']) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    if components.pop().is_none() {
+                        return Err(PathError::EscapesRoot(raw.to_string()));
+                    }
+                }
+                component if component.contains(':') => {
+                    return Err(PathError::DevicePrefix(raw.to_string()))
+                }
+                component => components.push(component.to_string()),
+            }
+        }
+        Ok(Self { components })
+    }
+
+    /// Whether the path refers to the root itself.
+    pub fn is_root(&self) -> bool {
+        self.components.is_empty()
+    }
+
+    /// The normalized path with `/` separators, empty for the root.
+    pub fn relative(&self) -> String {
+        self.components.join("/")
+    }
+
+    /// Maps the path onto `root`.
+    pub fn under(&self, root: &str) -> PathBuf {
+        let mut path = PathBuf::from(root);
+        path.extend(&self.components);
+        path
+    }
+}
+
+impl fmt::Display for RequestPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.relative())
+    }
+}
+
+/// Validates a request path and maps it onto `root`.
+pub fn resolve(root: &str, raw: &str) -> Result<PathBuf, PathError> {
+    Ok(RequestPath::parse(raw)?.under(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    const DATA_ROOT: &str = "rom:/Data";
+
+    fn relative(raw: &str) -> String {
+        RequestPath::parse(raw).unwrap().relative()
+    }
+
+    fn error(raw: &str) -> PathError {
+        RequestPath::parse(raw).unwrap_err()
+    }
+
+    #[test]
+    fn plain_paths_are_unchanged() {
+        assert_eq!(relative("xml"), "xml");
+        assert_eq!(relative("xml/Person.xml.bundle"), "xml/Person.xml.bundle");
+        assert_eq!(relative("message/en/名前.txt"), "message/en/名前.txt");
+        assert_eq!(relative("a b/c d.txt"), "a b/c d.txt");
+    }
+
+    #[test]
+    fn empty_and_dot_paths_are_the_root() {
+        for raw in ["", ".", "/", "\\", "./", "//", "./././", "a/.."] {
+            let path = RequestPath::parse(raw).unwrap();
+            assert!(path.is_root(), "{:?}", raw);
+            assert_eq!(path.relative(), "");
+        }
+        assert!(!RequestPath::parse("a").unwrap().is_root());
+    }
+
+    #[test]
+    fn backslashes_are_separators() {
+        assert_eq!(relative("xml\\Person.xml"), "xml/Person.xml");
+        assert_eq!(relative("xml\\sub/Person.xml"), "xml/sub/Person.xml");
+    }
+
+    #[test]
+    fn redundant_separators_and_dots_are_dropped() {
+        assert_eq!(relative("xml//Person.xml"), "xml/Person.xml");
+        assert_eq!(relative("./xml/./Person.xml"), "xml/Person.xml");
+        assert_eq!(relative("xml/"), "xml");
+        assert_eq!(relative("/xml"), "xml");
+        assert_eq!(relative("\\\\xml\\\\"), "xml");
+    }
+
+    #[test]
+    fn parent_components_inside_the_root_are_resolved() {
+        assert_eq!(relative("xml/../message"), "message");
+        assert_eq!(relative("a/b/../../c"), "c");
+        assert_eq!(relative("a/b/../c/./d/.."), "a/c");
+        assert_eq!(relative("a\\..\\b"), "b");
+    }
+
+    #[test]
+    fn parent_components_above_the_root_are_rejected() {
+        for raw in [
+            "..",
+            "../",
+            "../xml",
+            "/..",
+            "/../xml",
+            "xml/../..",
+            "xml/../../Data/xml",
+            "a/b/../../../c",
+            "..\\xml",
+            "./../xml",
+            "a/../../a",
+        ] {
+            assert_eq!(
+                error(raw),
+                PathError::EscapesRoot(raw.to_string()),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn device_prefixes_are_rejected() {
+        for raw in [
+            "rom:/Data/xml",
+            "rom:",
+            "sd:/engage/mods",
+            "C:\\Windows",
+            "c:",
+            "xml/sd:/x",
+            "\\\\?\\C:\\x",
+            "host:file",
+            "a/b:c",
+            "save:/",
+        ] {
+            assert_eq!(
+                error(raw),
+                PathError::DevicePrefix(raw.to_string()),
+                "{:?}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn nul_bytes_are_rejected() {
+        for raw in ["\0", "xml\0", "xml/\0/a", "a.bundle\0.txt"] {
+            assert_eq!(error(raw), PathError::NulByte(raw.to_string()), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn nul_bytes_win_over_other_errors() {
+        assert!(matches!(error("../\0"), PathError::NulByte(_)));
+        assert!(matches!(error("rom:\0"), PathError::NulByte(_)));
+    }
+
+    #[test]
+    fn names_made_of_dots_are_not_parent_components() {
+        assert_eq!(relative("..."), "...");
+        assert_eq!(relative("a/.../b"), "a/.../b");
+        assert_eq!(relative("..a/b.."), "..a/b..");
+    }
+
+    #[test]
+    fn paths_map_onto_roots() {
+        assert_eq!(
+            resolve(DATA_ROOT, "xml\\Person.xml").unwrap(),
+            Path::new("rom:/Data/xml/Person.xml")
+        );
+        assert_eq!(resolve(DATA_ROOT, "").unwrap(), Path::new("rom:/Data"));
+        assert_eq!(
+            RequestPath::parse("a/b")
+                .unwrap()
+                .under("sd:/engage/mods/astra/Data"),
+            Path::new("sd:/engage/mods/astra/Data/a/b")
+        );
+        assert!(resolve(DATA_ROOT, "../../sd:/x").is_err());
+    }
+
+    #[test]
+    fn mapped_paths_never_leave_the_root() {
+        let candidates = [
+            "", ".", "..", "a", "b:", "/", "\\", "\0", "a/..", "../..", "x/./y",
+        ];
+        for a in candidates {
+            for b in candidates {
+                for c in candidates {
+                    let raw = format!("{}/{}\\{}", a, b, c);
+                    if let Ok(path) = resolve(DATA_ROOT, &raw) {
+                        assert!(path.starts_with(DATA_ROOT), "{:?}", raw);
+                        let relative = path.strip_prefix(DATA_ROOT).unwrap();
+                        assert!(relative.iter().all(|c| c != ".." && c != "."), "{:?}", raw);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn display_is_the_normalized_path() {
+        assert_eq!(
+            RequestPath::parse("\\xml\\.\\a.xml").unwrap().to_string(),
+            "xml/a.xml"
+        );
+    }
+}
@@ -0,0 +1,1302 @@
+//! Wire format shared by the file server and its clients.
+//!
+//! Every integer is big-endian. Byte lengths are u64 and counts inside new frames are
+//! u32 unless noted otherwise. Strings sent by the client are UTF-8 lines terminated by
+//! `\n`, without control characters and no longer than the limits advertised in the
+//! handshake. Surrounding whitespace is ignored.
+//!
+//! # Handshake
+//!
+//! Client: magic `ACFS`, protocol version (u16).
+//!
+//! Server: magic `ACFS`, status (u8), protocol version (u16), server build (u16 length +
+//! UTF-8), supported opcodes (u8 count + one u8 each), limits (u8 count + u8 id and u64
+//! value each).
+//!
+//! The limits are listed in [`Limit`]. A value of 0 for RequestsPerConnection means
+//! unlimited: the session serves requests until the client sends Goodbye or disconnects.
+//! The first version 1 servers sent 1 there and closed the session after one request, so
+//! clients must not read 0 as "no requests".
+//!
+//! When the server requires authentication it answers a supported version with status 2
+//! and appends a 32-byte challenge to its hello. The client replies with
+//! HMAC-SHA256(token, challenge) (32 bytes) and the server answers with status 0, or with
+//! an error frame before closing the connection. Legacy clients can't authenticate and are
+//! sent an error frame.
+//!
+//! A server already serving its connection limit doesn't read the hello. Right after
+//! accepting it sends an error frame with code Busy (status 1, see below) and closes the
+//! connection. Clients tell it apart from a hello by its first byte, 1 instead of the `A`
+//! of the magic, and legacy clients read it like the error response to their request.
+//!
+//! # Requests
+//!
+//! An opcode (u8) followed by the arguments of the operation:
+//!
+//! | Opcode | Operation       | Arguments                                           |
+//! |--------|-----------------|-----------------------------------------------------|
+//! | 0      | Exists          | path line                                           |
+//! | 1      | Read            | path line                                           |
+//! | 2      | List            | directory line, glob line                           |
+//! | 3      | Goodbye         | none                                                |
+//! | 4      | ReadRange       | path line, offset (u64), length (u64)               |
+//! | 5      | Stat            | path line                                           |
+//! | 6      | StatBatch       | path count (u32), one path line each                |
+//! | 7      | Hash            | path line                                           |
+//! | 8      | HashBatch       | path count (u32), one path line each                |
+//! | 9      | ReadIfChanged   | path line, SHA-256 the client has cached (32 bytes) |
+//! | 10     | Write           | path line, length (u64), contents                   |
+//! | 11     | DeleteFile      | path line                                           |
+//! | 12     | DeleteDirectory | path line, recursive (u8)                           |
+//! | 13     | Rename          | source path line, destination path line             |
+//! | 14     | MakeDirectory   | path line                                           |
+//! | 15     | ModList         | directory line, glob line                           |
+//! | 16     | ModStatBatch    | path count (u32), one path line each                |
+//! | 17     | ModHashBatch    | path count (u32), one path line each                |
+//! | 18     | ListDir         | directory line                                      |
+//!
+//! Write, DeleteFile, DeleteDirectory, Rename and MakeDirectory paths are relative to the
+//! server's mod root rather than `rom:/Data`, and may not leave it. ModList, ModStatBatch
+//! and ModHashBatch work like List, StatBatch and HashBatch on the mod root, so clients
+//! can compare it with a local folder. Their directory and paths may name the mod root
+//! itself.
+//!
+//! The glob line of List holds comma-separated patterns matched against paths relative to
+//! the directory, where a leading `!` excludes matches. An empty line lists everything.
+//!
+//! # Responses
+//!
+//! | Operation     | Layout                                                                       |
+//! |---------------|------------------------------------------------------------------------------|
+//! | Exists        | u8, 1 if the path exists and 0 otherwise                                     |
+//! | Read          | status 0, length (u64), file contents                                        |
+//! | List          | status 0, path count (u64), one `\n`-terminated path each                    |
+//! | Goodbye       | status 0                                                                     |
+//! | ReadRange     | status 0, length (u64), the requested bytes                                  |
+//! | Stat          | status 0, stat record                                                        |
+//! | StatBatch     | status 0, count (u32), one stat record per path                              |
+//! | Hash          | status 0, SHA-256 (32 bytes)                                                 |
+//! | HashBatch     | status 0, count (u32), one hash record per path                              |
+//! | ListDir       | status 0, count (u32), one directory entry per child                         |
+//! | ReadIfChanged | status 0, changed (u8), and only if changed: SHA-256, length (u64), contents |
+//! | Write         | status 0, written length (u64), SHA-256 of the written contents              |
+//! | (failure)     | status 1, error frame                                                        |
+//!
+//! DeleteFile, DeleteDirectory, Rename and MakeDirectory answer with status 0 alone.
+//! ModList, ModStatBatch and ModHashBatch answer like List, StatBatch and HashBatch, with
+//! listed paths relative to the mod root.
+//!
+//! An error frame is the message (u64 length + UTF-8), the error code (u16, see
+//! [`ErrorCode`](crate::error::ErrorCode)) and context such as the path involved (u64
+//! length + UTF-8, empty when there is none). The message comes first so legacy clients,
+//! which stop reading after it, still understand the frame. Sessions negotiated at
+//! version 1 are sent the message alone, as before error codes existed, since anything
+//! after it would be read as the start of the next response.
+//!
+//! A stat record is the entry kind (u8: 0 missing, 1 file, 2 directory), the size in bytes
+//! (u64), whether the modification time is known (u8) and the modification time in seconds
+//! since the Unix epoch (u64, zero when unknown). A hash record is whether a file exists at
+//! the path (u8, 0 for directories) and its SHA-256 (32 bytes, zero when missing). Batch
+//! responses list records in request order. A directory entry is the child's name (u16
+//! length + UTF-8) followed by its stat record, and ListDir sends them sorted by name.
+//!
+//! # Discovery
+//!
+//! Clients find consoles on the local network by broadcasting a UDP probe, the magic
+//! `ACFD`, to the discovery port. Each server answers the sender with a beacon: magic
+//! `ACFD`, protocol version (u16), TCP port of the file server (u16), title id of the game
+//! (u64) and the console's nickname (u16 length + UTF-8).
+//!
+//! Legacy clients skip the handshake and send a single request per connection. The u64
+//! fields in the legacy responses match the `usize` the server wrote on the Switch before
+//! the widths were fixed, so those clients keep working unchanged.
+
+use crate::auth::Challenge;
+use crate::error::{ErrorCode, ServerError};
+use anyhow::{bail, Result};
+use std::io::{self, BufRead, ErrorKind, Read, Write};
+
+/// Magic bytes that open a handshake frame. The first byte doubles as the marker that
+/// separates negotiated clients from legacy clients, which start with a raw opcode.
+pub const HANDSHAKE_MAGIC: &[u8; 4] = b"ACFS";
+
+/// Version of the protocol spoken after a successful handshake.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest client protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// First protocol version whose error frames carry a code and context. Sessions of older
+/// clients get frames that hold only the message.
+pub const CODED_ERRORS_VERSION: u16 = 2;
+
+pub const SERVER_BUILD: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Magic bytes that open discovery probes and beacons.
+pub const DISCOVERY_MAGIC: &[u8; 4] = b"ACFD";
+
+/// Fire Emblem Engage, matching `package.metadata.skyline` in Cargo.toml. Beacons advertise
+/// it unless `discovery.title_id` is configured.
+pub const TITLE_ID: u64 = 0x0100_a630_1214_e000;
+
+/// SHA-256 digest of a file's contents.
+pub type FileHash = [u8; 32];
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Operation {
+    Exists = 0,
+    Read = 1,
+    List = 2,
+    /// Ends a negotiated session. The server acknowledges and closes the connection.
+    Goodbye = 3,
+    /// Reads `length` bytes starting at `offset`. Fails if the range is not inside the
+    /// file.
+    ReadRange = 4,
+    Stat = 5,
+    /// Stats up to [`MAX_BATCH_PATHS`] paths at once.
+    StatBatch = 6,
+    Hash = 7,
+    /// Hashes up to [`MAX_BATCH_PATHS`] paths at once.
+    HashBatch = 8,
+    /// Sends the file only if its hash differs from the one the client already has.
+    ReadIfChanged = 9,
+    /// Uploads a file into the mod root, replacing any existing file.
+    Write = 10,
+    DeleteFile = 11,
+    /// Deletes a directory, including its contents only if the recursive flag is set.
+    DeleteDirectory = 12,
+    /// Moves a file or directory within the mod root. Never overwrites.
+    Rename = 13,
+    MakeDirectory = 14,
+    /// Lists files below a directory of the mod root.
+    ModList = 15,
+    /// Stats up to [`MAX_BATCH_PATHS`] paths in the mod root at once.
+    ModStatBatch = 16,
+    /// Hashes up to [`MAX_BATCH_PATHS`] paths in the mod root at once.
+    ModHashBatch = 17,
+    /// Lists only the immediate children of a directory, with their kind and size.
+    ListDir = 18,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 19] = [
+        Operation::Exists,
+        Operation::Read,
+        Operation::List,
+        Operation::Goodbye,
+        Operation::ReadRange,
+        Operation::Stat,
+        Operation::StatBatch,
+        Operation::Hash,
+        Operation::HashBatch,
+        Operation::ReadIfChanged,
+        Operation::Write,
+        Operation::DeleteFile,
+        Operation::DeleteDirectory,
+        Operation::Rename,
+        Operation::MakeDirectory,
+        Operation::ModList,
+        Operation::ModStatBatch,
+        Operation::ModHashBatch,
+        Operation::ListDir,
+    ];
+}
+
+impl TryFrom<u8> for Operation {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match Operation::ALL.iter().find(|op| **op as u8 == value) {
+            Some(op) => Ok(*op),
+            None => Err(ServerError::new(
+                ErrorCode::UnsupportedOperation,
+                format!(
+                    "Unknown operation {} (server {} supports operations {:?})",
+                    value,
+                    SERVER_BUILD,
+                    Operation::ALL.map(|op| op as u8)
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Limits advertised during the handshake as (id, value) pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Limit {
+    /// Number of requests served per connection. Zero means unlimited.
+    RequestsPerConnection = 0,
+    /// Number of paths accepted by a single batch request.
+    MaxBatchPaths = 1,
+    /// Bytes in a path line, not counting the terminator.
+    MaxPathLength = 2,
+    /// Bytes in the glob line of a List request, not counting the terminator.
+    MaxGlobLength = 3,
+}
+
+pub const MAX_BATCH_PATHS: u32 = 4096;
+pub const MAX_PATH_LENGTH: usize = 1024;
+pub const MAX_GLOB_LENGTH: usize = 4096;
+
+pub const LIMITS: [(Limit, u64); 4] = [
+    (Limit::RequestsPerConnection, 0),
+    (Limit::MaxBatchPaths, MAX_BATCH_PATHS as u64),
+    (Limit::MaxPathLength, MAX_PATH_LENGTH as u64),
+    (Limit::MaxGlobLength, MAX_GLOB_LENGTH as u64),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum HandshakeStatus {
+    Accepted = 0,
+    UnsupportedVersion = 1,
+    /// The client must answer the challenge in the hello before sending requests.
+    AuthRequired = 2,
+}
+
+impl TryFrom<u8> for HandshakeStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(HandshakeStatus::Accepted),
+            1 => Ok(HandshakeStatus::UnsupportedVersion),
+            2 => Ok(HandshakeStatus::AuthRequired),
+            _ => bail!("Unknown handshake status {}", value),
+        }
+    }
+}
+
+/// The server's answer to a handshake. Opcodes and limit ids are kept raw so clients can
+/// still decode a newer server's reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerHello {
+    pub status: HandshakeStatus,
+    pub version: u16,
+    pub build: String,
+    pub operations: Vec<u8>,
+    pub limits: Vec<(u8, u64)>,
+    /// Sent only with [`HandshakeStatus::AuthRequired`].
+    pub challenge: Option<Challenge>,
+}
+
+impl ServerHello {
+    pub fn new(status: HandshakeStatus) -> Self {
+        Self {
+            status,
+            version: PROTOCOL_VERSION,
+            build: SERVER_BUILD.to_string(),
+            operations: Operation::ALL.map(|op| op as u8).to_vec(),
+            limits: LIMITS
+                .iter()
+                .map(|(limit, value)| (*limit as u8, *value))
+                .collect(),
+            challenge: None,
+        }
+    }
+
+    pub fn auth_required(challenge: Challenge) -> Self {
+        Self {
+            challenge: Some(challenge),
+            ..Self::new(HandshakeStatus::AuthRequired)
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(HANDSHAKE_MAGIC)?;
+        writer.write_u8(self.status as u8)?;
+        writer.write_u16(self.version)?;
+        writer.write_u16(self.build.len() as u16)?;
+        writer.write_all(self.build.as_bytes())?;
+        writer.write_u8(self.operations.len() as u8)?;
+        writer.write_all(&self.operations)?;
+        writer.write_u8(self.limits.len() as u8)?;
+        for (id, value) in &self.limits {
+            writer.write_u8(*id)?;
+            writer.write_u64(*value)?;
+        }
+        if let Some(challenge) = &self.challenge {
+            writer.write_all(challenge)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != HANDSHAKE_MAGIC {
+            bail!("Invalid handshake magic {:?}", magic);
+        }
+        let status = HandshakeStatus::try_from(reader.read_u8()?)?;
+        let version = reader.read_u16()?;
+        let mut build = vec![0u8; reader.read_u16()? as usize];
+        reader.read_exact(&mut build)?;
+        let mut operations = vec![0u8; reader.read_u8()? as usize];
+        reader.read_exact(&mut operations)?;
+        let mut limits = Vec::new();
+        for _ in 0..reader.read_u8()? {
+            limits.push((reader.read_u8()?, reader.read_u64()?));
+        }
+        let challenge = match status {
+            HandshakeStatus::AuthRequired => {
+                let mut challenge = [0u8; 32];
+                reader.read_exact(&mut challenge)?;
+                Some(challenge)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            status,
+            version,
+            build: String::from_utf8(build)?,
+            operations,
+            limits,
+            challenge,
+        })
+    }
+}
+
+/// Reply to a discovery probe, telling the client where to connect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub version: u16,
+    pub port: u16,
+    pub title_id: u64,
+    pub nickname: String,
+}
+
+impl Beacon {
+    pub fn new(port: u16, title_id: u64, nickname: &str) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            port,
+            title_id,
+            nickname: nickname.to_string(),
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(DISCOVERY_MAGIC)?;
+        writer.write_u16(self.version)?;
+        writer.write_u16(self.port)?;
+        writer.write_u64(self.title_id)?;
+        writer.write_u16(self.nickname.len() as u16)?;
+        writer.write_all(self.nickname.as_bytes())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != DISCOVERY_MAGIC {
+            bail!("Invalid beacon magic {:?}", magic);
+        }
+        let version = reader.read_u16()?;
+        let port = reader.read_u16()?;
+        let title_id = reader.read_u64()?;
+        let mut nickname = vec![0u8; reader.read_u16()? as usize];
+        reader.read_exact(&mut nickname)?;
+        Ok(Self {
+            version,
+            port,
+            title_id,
+            nickname: String::from_utf8(nickname)?,
+        })
+    }
+}
+
+pub fn write_client_hello<W: Write>(writer: &mut W, version: u16) -> io::Result<()> {
+    writer.write_all(HANDSHAKE_MAGIC)?;
+    writer.write_u16(version)
+}
+
+/// Reads the client's handshake frame after its first magic byte was consumed as an
+/// opcode and returns the client's protocol version.
+pub fn read_client_hello<R: Read>(reader: &mut R) -> Result<u16> {
+    let mut magic = [0u8; 3];
+    reader.read_exact(&mut magic)?;
+    if magic != HANDSHAKE_MAGIC[1..] {
+        return Err(ServerError::new(
+            ErrorCode::InvalidRequest,
+            format!("Invalid handshake magic {:?}", magic),
+        )
+        .into());
+    }
+    Ok(reader.read_u16()?)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Exists {
+        path: String,
+    },
+    Read {
+        path: String,
+    },
+    List {
+        path: String,
+        glob: String,
+    },
+    Goodbye,
+    ReadRange {
+        path: String,
+        offset: u64,
+        length: u64,
+    },
+    Stat {
+        path: String,
+    },
+    StatBatch {
+        paths: Vec<String>,
+    },
+    Hash {
+        path: String,
+    },
+    HashBatch {
+        paths: Vec<String>,
+    },
+    ReadIfChanged {
+        path: String,
+        hash: FileHash,
+    },
+    /// Only the header of the frame; the `length` bytes of contents follow it.
+    Write {
+        path: String,
+        length: u64,
+    },
+    DeleteFile {
+        path: String,
+    },
+    DeleteDirectory {
+        path: String,
+        recursive: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    MakeDirectory {
+        path: String,
+    },
+    ModList {
+        path: String,
+        glob: String,
+    },
+    ModStatBatch {
+        paths: Vec<String>,
+    },
+    ModHashBatch {
+        paths: Vec<String>,
+    },
+    ListDir {
+        path: String,
+    },
+}
+
+impl Request {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Request::Exists { path } => {
+                writer.write_u8(Operation::Exists as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::Read { path } => {
+                writer.write_u8(Operation::Read as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::List { path, glob } => {
+                writer.write_u8(Operation::List as u8)?;
+                writeln!(writer, "{}", path)?;
+                writeln!(writer, "{}", glob)
+            }
+            Request::Goodbye => writer.write_u8(Operation::Goodbye as u8),
+            Request::ReadRange {
+                path,
+                offset,
+                length,
+            } => {
+                writer.write_u8(Operation::ReadRange as u8)?;
+                writeln!(writer, "{}", path)?;
+                writer.write_u64(*offset)?;
+                writer.write_u64(*length)
+            }
+            Request::Stat { path } => {
+                writer.write_u8(Operation::Stat as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::StatBatch { paths } => {
+                writer.write_u8(Operation::StatBatch as u8)?;
+                write_lines(writer, paths)
+            }
+            Request::Hash { path } => {
+                writer.write_u8(Operation::Hash as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::HashBatch { paths } => {
+                writer.write_u8(Operation::HashBatch as u8)?;
+                write_lines(writer, paths)
+            }
+            Request::ReadIfChanged { path, hash } => {
+                writer.write_u8(Operation::ReadIfChanged as u8)?;
+                writeln!(writer, "{}", path)?;
+                writer.write_all(hash)
+            }
+            Request::Write { path, length } => {
+                writer.write_u8(Operation::Write as u8)?;
+                writeln!(writer, "{}", path)?;
+                writer.write_u64(*length)
+            }
+            Request::DeleteFile { path } => {
+                writer.write_u8(Operation::DeleteFile as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::DeleteDirectory { path, recursive } => {
+                writer.write_u8(Operation::DeleteDirectory as u8)?;
+                writeln!(writer, "{}", path)?;
+                writer.write_u8(*recursive as u8)
+            }
+            Request::Rename { from, to } => {
+                writer.write_u8(Operation::Rename as u8)?;
+                writeln!(writer, "{}", from)?;
+                writeln!(writer, "{}", to)
+            }
+            Request::MakeDirectory { path } => {
+                writer.write_u8(Operation::MakeDirectory as u8)?;
+                writeln!(writer, "{}", path)
+            }
+            Request::ModList { path, glob } => {
+                writer.write_u8(Operation::ModList as u8)?;
+                writeln!(writer, "{}", path)?;
+                writeln!(writer, "{}", glob)
+            }
+            Request::ModStatBatch { paths } => {
+                writer.write_u8(Operation::ModStatBatch as u8)?;
+                write_lines(writer, paths)
+            }
+            Request::ModHashBatch { paths } => {
+                writer.write_u8(Operation::ModHashBatch as u8)?;
+                write_lines(writer, paths)
+            }
+            Request::ListDir { path } => {
+                writer.write_u8(Operation::ListDir as u8)?;
+                writeln!(writer, "{}", path)
+            }
+        }
+    }
+
+    /// Reads the rest of a request frame so the next request starts on a clean boundary.
+    pub fn read<R: BufRead>(opcode: u8, reader: &mut R) -> Result<Self> {
+        Ok(match Operation::try_from(opcode)? {
+            Operation::Exists => Request::Exists {
+                path: read_path(reader)?,
+            },
+            Operation::Read => Request::Read {
+                path: read_path(reader)?,
+            },
+            Operation::List => Request::List {
+                path: read_path(reader)?,
+                glob: read_line(reader, MAX_GLOB_LENGTH)?,
+            },
+            Operation::Goodbye => Request::Goodbye,
+            Operation::ReadRange => Request::ReadRange {
+                path: read_path(reader)?,
+                offset: reader.read_u64()?,
+                length: reader.read_u64()?,
+            },
+            Operation::Stat => Request::Stat {
+                path: read_path(reader)?,
+            },
+            Operation::StatBatch => Request::StatBatch {
+                paths: read_lines(reader)?,
+            },
+            Operation::Hash => Request::Hash {
+                path: read_path(reader)?,
+            },
+            Operation::HashBatch => Request::HashBatch {
+                paths: read_lines(reader)?,
+            },
+            Operation::ReadIfChanged => {
+                let path = read_path(reader)?;
+                let mut hash = FileHash::default();
+                reader.read_exact(&mut hash)?;
+                Request::ReadIfChanged { path, hash }
+            }
+            Operation::Write => Request::Write {
+                path: read_path(reader)?,
+                length: reader.read_u64()?,
+            },
+            Operation::DeleteFile => Request::DeleteFile {
+                path: read_path(reader)?,
+            },
+            Operation::DeleteDirectory => Request::DeleteDirectory {
+                path: read_path(reader)?,
+                recursive: reader.read_u8()? != 0,
+            },
+            Operation::Rename => Request::Rename {
+                from: read_path(reader)?,
+                to: read_path(reader)?,
+            },
+            Operation::MakeDirectory => Request::MakeDirectory {
+                path: read_path(reader)?,
+            },
+            Operation::ModList => Request::ModList {
+                path: read_path(reader)?,
+                glob: read_line(reader, MAX_GLOB_LENGTH)?,
+            },
+            Operation::ModStatBatch => Request::ModStatBatch {
+                paths: read_lines(reader)?,
+            },
+            Operation::ModHashBatch => Request::ModHashBatch {
+                paths: read_lines(reader)?,
+            },
+            Operation::ListDir => Request::ListDir {
+                path: read_path(reader)?,
+            },
+        })
+    }
+}
+
+/// Reads the next opcode, returning `None` if the client closed the connection.
+pub fn read_opcode<R: Read>(reader: &mut R) -> Result<Option<u8>> {
+    match reader.read_u8() {
+        Ok(opcode) => Ok(Some(opcode)),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn read_path<R: BufRead>(reader: &mut R) -> Result<String> {
+    read_line(reader, MAX_PATH_LENGTH)
+}
+
+/// Reads a line of at most `max_length` bytes, not counting the terminator, and trims
+/// it. The line must be UTF-8 without control characters. A longer line is rejected
+/// without buffering the rest of it.
+fn read_line<R: BufRead>(reader: &mut R, max_length: usize) -> Result<String> {
+    let mut line = Vec::new();
+    reader
+        .take(max_length as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > max_length {
+        return Err(ServerError::new(
+            ErrorCode::TooLarge,
+            format!("Line exceeds the limit of {} bytes", max_length),
+        )
+        .into());
+    }
+    let line = String::from_utf8(line)
+        .map_err(|_| ServerError::new(ErrorCode::InvalidRequest, "Line is not valid UTF-8"))?;
+    let line = line.trim();
+    if line.chars().any(char::is_control) {
+        return Err(ServerError::new(
+            ErrorCode::InvalidRequest,
+            format!("Line {:?} contains control characters", line),
+        )
+        .into());
+    }
+    Ok(line.to_string())
+}
+
+fn write_lines<W: Write>(writer: &mut W, lines: &[String]) -> io::Result<()> {
+    writer.write_u32(lines.len() as u32)?;
+    for line in lines {
+        writeln!(writer, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Reads a u32 count followed by that many lines, refusing counts above
+/// [`MAX_BATCH_PATHS`].
+fn read_lines<R: BufRead>(reader: &mut R) -> Result<Vec<String>> {
+    let count = reader.read_u32()?;
+    if count > MAX_BATCH_PATHS {
+        return Err(ServerError::new(
+            ErrorCode::TooLarge,
+            format!(
+                "Batch of {} paths exceeds the limit of {}",
+                count, MAX_BATCH_PATHS
+            ),
+        )
+        .into());
+    }
+    (0..count).map(|_| read_path(reader)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EntryKind {
+    Missing = 0,
+    File = 1,
+    Directory = 2,
+}
+
+impl TryFrom<u8> for EntryKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(EntryKind::Missing),
+            1 => Ok(EntryKind::File),
+            2 => Ok(EntryKind::Directory),
+            _ => bail!("Unknown entry kind {}", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub kind: EntryKind,
+    pub size: u64,
+    /// Seconds since the Unix epoch, if the filesystem reports it.
+    pub modified: Option<u64>,
+}
+
+impl FileStat {
+    pub const MISSING: FileStat = FileStat {
+        kind: EntryKind::Missing,
+        size: 0,
+        modified: None,
+    };
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u8(self.kind as u8)?;
+        writer.write_u64(self.size)?;
+        writer.write_u8(self.modified.is_some() as u8)?;
+        writer.write_u64(self.modified.unwrap_or_default())
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let kind = EntryKind::try_from(reader.read_u8()?)?;
+        let size = reader.read_u64()?;
+        let has_modified = reader.read_u8()? != 0;
+        let modified = reader.read_u64()?;
+        Ok(Self {
+            kind,
+            size,
+            modified: has_modified.then_some(modified),
+        })
+    }
+}
+
+/// A child of a directory listed by [`Operation::ListDir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub stat: FileStat,
+}
+
+impl DirEntry {
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u16(self.name.len() as u16)?;
+        writer.write_all(self.name.as_bytes())?;
+        self.stat.write(writer)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut name = vec![0u8; reader.read_u16()? as usize];
+        reader.read_exact(&mut name)?;
+        Ok(Self {
+            name: String::from_utf8(name)?,
+            stat: FileStat::read(reader)?,
+        })
+    }
+}
+
+pub fn write_ok<W: Write>(writer: &mut W) -> io::Result<()> {
+    writer.write_u8(STATUS_OK)
+}
+
+pub trait ReadExt: Read {
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+impl<R: Read + ?Sized> ReadExt for R {}
+
+pub trait WriteExt: Write {
+    fn write_u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_all(&[value])
+    }
+
+    fn write_u16(&mut self, value: u16) -> io::Result<()> {
+        self.write_all(&value.to_be_bytes())
+    }
+
+    fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        self.write_all(&value.to_be_bytes())
+    }
+
+    fn write_u64(&mut self, value: u64) -> io::Result<()> {
+        self.write_all(&value.to_be_bytes())
+    }
+}
+
+impl<W: Write + ?Sized> WriteExt for W {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn integers_are_big_endian_and_fixed_width() {
+        let mut buf = Vec::new();
+        buf.write_u16(0x0102).unwrap();
+        buf.write_u32(0x03040506).unwrap();
+        buf.write_u64(0x0708090a0b0c0d0e).unwrap();
+        assert_eq!(buf, (1..=14).collect::<Vec<u8>>());
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(cursor.read_u16().unwrap(), 0x0102);
+        assert_eq!(cursor.read_u32().unwrap(), 0x03040506);
+        assert_eq!(cursor.read_u64().unwrap(), 0x0708090a0b0c0d0e);
+    }
+
+    #[test]
+    fn server_hello_round_trip() {
+        let hello = ServerHello::new(HandshakeStatus::Accepted);
+        let mut buf = Vec::new();
+        hello.write(&mut buf).unwrap();
+        assert_eq!(&buf[..4], HANDSHAKE_MAGIC);
+        assert_eq!(ServerHello::read(&mut Cursor::new(buf)).unwrap(), hello);
+    }
+
+    #[test]
+    fn server_hello_carries_the_challenge_only_when_auth_is_required() {
+        let mut accepted = Vec::new();
+        ServerHello::new(HandshakeStatus::Accepted)
+            .write(&mut accepted)
+            .unwrap();
+        let hello = ServerHello::auth_required([7; 32]);
+        let mut buf = Vec::new();
+        hello.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), accepted.len() + 32);
+        assert_eq!(buf[4], HandshakeStatus::AuthRequired as u8);
+        assert_eq!(ServerHello::read(&mut Cursor::new(buf)).unwrap(), hello);
+    }
+
+    #[test]
+    fn beacon_round_trip() {
+        let beacon = Beacon::new(7878, TITLE_ID, "Living room");
+        let mut buf = Vec::new();
+        beacon.write(&mut buf).unwrap();
+        assert_eq!(&buf[..4], DISCOVERY_MAGIC);
+        assert_eq!(
+            &buf[8..16],
+            [0x01, 0x00, 0xa6, 0x30, 0x12, 0x14, 0xe0, 0x00]
+        );
+        assert_eq!(Beacon::read(&mut Cursor::new(buf)).unwrap(), beacon);
+    }
+
+    #[test]
+    fn client_hello_round_trip() {
+        let mut buf = Vec::new();
+        write_client_hello(&mut buf, 7).unwrap();
+        assert_eq!(buf, [b'A', b'C', b'F', b'S', 0, 7]);
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_opcode(&mut cursor).unwrap(), Some(HANDSHAKE_MAGIC[0]));
+        assert_eq!(read_client_hello(&mut cursor).unwrap(), 7);
+    }
+
+    #[test]
+    fn client_hello_rejects_bad_magic() {
+        assert!(read_client_hello(&mut Cursor::new(b"CFX\x00\x01")).is_err());
+    }
+
+    #[test]
+    fn requests_round_trip_back_to_back() {
+        let requests = [
+            Request::Exists {
+                path: "xml/Person.xml".to_string(),
+            },
+            Request::Read {
+                path: "message/en.bundle".to_string(),
+            },
+            Request::List {
+                path: "xml".to_string(),
+                glob: "*.xml".to_string(),
+            },
+            Request::ReadRange {
+                path: "data.bundle".to_string(),
+                offset: 0x1234,
+                length: u64::MAX,
+            },
+            Request::Stat {
+                path: "xml".to_string(),
+            },
+            Request::StatBatch {
+                paths: vec!["a.xml".to_string(), "b/c.bundle".to_string()],
+            },
+            Request::StatBatch { paths: Vec::new() },
+            Request::Hash {
+                path: "a.xml".to_string(),
+            },
+            Request::HashBatch {
+                paths: vec!["a.xml".to_string()],
+            },
+            Request::ReadIfChanged {
+                path: "a.xml".to_string(),
+                hash: [0xab; 32],
+            },
+            Request::Write {
+                path: "xml/Person.xml".to_string(),
+                length: 1 << 33,
+            },
+            Request::DeleteFile {
+                path: "a.xml".to_string(),
+            },
+            Request::DeleteDirectory {
+                path: "xml".to_string(),
+                recursive: true,
+            },
+            Request::Rename {
+                from: "a.xml".to_string(),
+                to: "b/a.xml".to_string(),
+            },
+            Request::MakeDirectory {
+                path: "b".to_string(),
+            },
+            Request::ModList {
+                path: String::new(),
+                glob: "xml/**".to_string(),
+            },
+            Request::ModStatBatch {
+                paths: vec!["a.xml".to_string()],
+            },
+            Request::ModHashBatch {
+                paths: vec!["a.xml".to_string(), "b/c.xml".to_string()],
+            },
+            Request::ListDir {
+                path: "xml".to_string(),
+            },
+            Request::Goodbye,
+        ];
+        let mut buf = Vec::new();
+        for request in &requests {
+            request.write(&mut buf).unwrap();
+        }
+
+        let mut cursor = Cursor::new(buf);
+        for request in &requests {
+            let opcode = read_opcode(&mut cursor).unwrap().unwrap();
+            assert_eq!(&Request::read(opcode, &mut cursor).unwrap(), request);
+        }
+        assert_eq!(read_opcode(&mut cursor).unwrap(), None);
+    }
+
+    #[test]
+    fn legacy_request_lines_are_trimmed() {
+        let mut cursor = Cursor::new(b"xml\\Person.xml\r\n".to_vec());
+        assert_eq!(
+            Request::read(1, &mut cursor).unwrap(),
+            Request::Read {
+                path: "xml\\Person.xml".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_batch_is_rejected_before_reading_paths() {
+        let mut buf = Vec::new();
+        buf.write_u32(MAX_BATCH_PATHS + 1).unwrap();
+        let err = Request::read(Operation::StatBatch as u8, &mut Cursor::new(buf)).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"));
+    }
+
+    fn read_path_line(bytes: &[u8]) -> Result<String> {
+        read_path(&mut Cursor::new(bytes))
+    }
+
+    fn error_code(result: Result<String>) -> ErrorCode {
+        result.unwrap_err().downcast::<ServerError>().unwrap().code
+    }
+
+    #[test]
+    fn lines_up_to_the_limit_are_accepted() {
+        let path = "a".repeat(MAX_PATH_LENGTH);
+        assert_eq!(
+            read_path_line(format!("{}\n", path).as_bytes()).unwrap(),
+            path
+        );
+        assert_eq!(read_path_line(path.as_bytes()).unwrap(), path);
+        assert_eq!(read_path_line(b" xml/a.xml \r\n").unwrap(), "xml/a.xml");
+    }
+
+    #[test]
+    fn long_lines_are_rejected_without_buffering_them() {
+        let path = "a".repeat(MAX_PATH_LENGTH + 1);
+        assert_eq!(
+            error_code(read_path_line(format!("{}\n", path).as_bytes())),
+            ErrorCode::TooLarge
+        );
+
+        let mut cursor = Cursor::new(vec![b'a'; 16 * 1024 * 1024]);
+        assert!(read_path(&mut cursor).is_err());
+        assert_eq!(cursor.position(), MAX_PATH_LENGTH as u64 + 1);
+
+        let glob = "*".repeat(MAX_GLOB_LENGTH);
+        let mut buf = format!("xml\n{}\n", glob).into_bytes();
+        let request = Request::read(Operation::List as u8, &mut Cursor::new(&buf)).unwrap();
+        assert_eq!(
+            request,
+            Request::List {
+                path: "xml".to_string(),
+                glob
+            }
+        );
+        buf.insert(4, b'*');
+        assert!(Request::read(Operation::List as u8, &mut Cursor::new(&buf)).is_err());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert_eq!(
+            error_code(read_path_line(b"xml/\xff\xfe.xml\n")),
+            ErrorCode::InvalidRequest
+        );
+        for line in [
+            &b"xml/\x1b[2J\n"[..],
+            b"a\x00b\n",
+            b"a\rb\n",
+            b"a\x7fb\n",
+            b"a\tb\n",
+        ] {
+            assert_eq!(
+                error_code(read_path_line(line)),
+                ErrorCode::InvalidRequest,
+                "{:?}",
+                line
+            );
+        }
+        assert_eq!(read_path_line("名前.txt\n".as_bytes()).unwrap(), "名前.txt");
+    }
+
+    #[test]
+    fn stat_record_round_trip() {
+        for stat in [
+            FileStat::MISSING,
+            FileStat {
+                kind: EntryKind::File,
+                size: 1 << 40,
+                modified: Some(1_700_000_000),
+            },
+            FileStat {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: None,
+            },
+        ] {
+            let mut buf = Vec::new();
+            stat.write(&mut buf).unwrap();
+            assert_eq!(buf.len(), 18);
+            assert_eq!(FileStat::read(&mut Cursor::new(buf)).unwrap(), stat);
+        }
+    }
+
+    #[test]
+    fn dir_entry_round_trip() {
+        let entry = DirEntry {
+            name: "名前.xml".to_string(),
+            stat: FileStat {
+                kind: EntryKind::File,
+                size: 42,
+                modified: None,
+            },
+        };
+        let mut buf = Vec::new();
+        entry.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), 2 + entry.name.len() + 18);
+        assert_eq!(DirEntry::read(&mut Cursor::new(buf)).unwrap(), entry);
+    }
+
+    #[test]
+    fn unknown_opcode_lists_supported_operations() {
+        let err = Request::read(42, &mut Cursor::new(Vec::new())).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18]"));
+    }
+}
+
+/// Fuzzing harness for the request parser. Feeds random and mutated request streams
+/// through an in-memory reader, checking that parsing never panics, never reads past the
+/// input and that everything it accepts survives a round trip. Runs a short pass with the
+/// unit tests; set `FUZZ_ITERATIONS` (and optionally `FUZZ_SEED`) for a longer run:
+///
+/// ```text
+/// FUZZ_ITERATIONS=10000000 cargo test --release fuzz_request_parser
+/// ```
+#[cfg(test)]
+mod fuzz {
+    use super::*;
+    use std::io::Cursor;
+
+    /// xorshift64*, so failures can be replayed from the seed alone.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    fn env_or(name: &str, default: u64) -> u64 {
+        std::env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+
+    fn seed_requests() -> Vec<Vec<u8>> {
+        let requests = [
+            Request::Exists {
+                path: "xml/Person.xml.bundle".to_string(),
+            },
+            Request::List {
+                path: "xml".to_string(),
+                glob: "*.bundle,!Person*".to_string(),
+            },
+            Request::ReadRange {
+                path: "a".to_string(),
+                offset: 1,
+                length: u64::MAX,
+            },
+            Request::StatBatch {
+                paths: vec!["a".to_string(), "名前".to_string()],
+            },
+            Request::ReadIfChanged {
+                path: "b".to_string(),
+                hash: [0xab; 32],
+            },
+            Request::Write {
+                path: "c".to_string(),
+                length: 3,
+            },
+            Request::DeleteDirectory {
+                path: "d".to_string(),
+                recursive: true,
+            },
+            Request::Rename {
+                from: "e".to_string(),
+                to: "f".to_string(),
+            },
+            Request::ModHashBatch {
+                paths: vec!["g".to_string(), String::new()],
+            },
+            Request::ListDir {
+                path: String::new(),
+            },
+            Request::Goodbye,
+        ];
+        requests
+            .iter()
+            .map(|request| {
+                let mut buf = Vec::new();
+                request.write(&mut buf).unwrap();
+                buf
+            })
+            .collect()
+    }
+
+    fn mutate(rng: &mut Rng, input: &mut Vec<u8>) {
+        for _ in 0..=rng.below(4) {
+            let at = rng.below(input.len() + 1);
+            match rng.below(5) {
+                0 if at < input.len() => input[at] = rng.next() as u8,
+                1 if at < input.len() => {
+                    input.remove(at);
+                }
+                2 => input.insert(at, [b'\n', 0, 0xff, b'/', b'.'][rng.below(5)]),
+                3 => input.truncate(at),
+                _ => {
+                    let bytes: Vec<u8> = (0..rng.below(32)).map(|_| rng.next() as u8).collect();
+                    input.splice(at..at, bytes);
+                }
+            }
+        }
+    }
+
+    /// Parses every request in `input`, returning what was accepted.
+    fn parse_all(input: &[u8]) -> Vec<Request> {
+        let mut cursor = Cursor::new(input);
+        let mut requests = Vec::new();
+        while let Ok(Some(opcode)) = read_opcode(&mut cursor) {
+            match Request::read(opcode, &mut cursor) {
+                Ok(request) => requests.push(request),
+                Err(_) => break,
+            }
+        }
+        assert!(cursor.position() <= input.len() as u64);
+        requests
+    }
+
+    #[test]
+    fn fuzz_request_parser() {
+        let seed = env_or("FUZZ_SEED", 0x5eed_a57a);
+        let iterations = env_or("FUZZ_ITERATIONS", 20_000);
+        let mut rng = Rng(seed | 1);
+        let seeds = seed_requests();
+
+        for iteration in 0..iterations {
+            let mut input = match rng.below(3) {
+                0 => (0..rng.below(256)).map(|_| rng.next() as u8).collect(),
+                _ => {
+                    let mut input = Vec::new();
+                    for _ in 0..=rng.below(3) {
+                        input.extend_from_slice(&seeds[rng.below(seeds.len())]);
+                    }
+                    input
+                }
+            };
+            mutate(&mut rng, &mut input);
+
+            for request in parse_all(&input) {
+                let mut encoded = Vec::new();
+                request.write(&mut encoded).unwrap();
+                assert_eq!(
+                    parse_all(&encoded),
+                    [request],
+                    "round trip failed at iteration {} with seed {:#x}",
+                    iteration,
+                    seed
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,1995 @@
+use crate::access::AccessControl;
+use crate::auth;
+use crate::config::ServerConfig;
+use crate::discovery;
+use crate::error::{ErrorCode, ServerError};
+use crate::fs::{FileSystem, ReadFile, WriteFile};
+use crate::glob_filter::GlobFilter;
+use crate::logger::Logger;
+use crate::mod_fs;
+use crate::paths::{self, RequestPath};
+use crate::protocol::{
+    self, DirEntry, EntryKind, FileHash, FileStat, HandshakeStatus, Request, ServerHello, WriteExt,
+};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Size of the buffer file contents are streamed through.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Numbers the temporary files of uploads, so concurrent uploads of the same path never
+/// write to the same temporary file.
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Loads the config file at `path` and runs the server with it. The defaults are only
+/// used when the file doesn't exist. A file that can't be read or is invalid stops the
+/// server from starting, since serving with the defaults would drop its auth token and
+/// allowlist.
+pub fn run_with_config_file(path: &str, fs: Arc<dyn FileSystem>) {
+    match ServerConfig::load(path) {
+        Ok(config) => {
+            let logger = Arc::new(Logger::new(&config.log));
+            run_server(config, fs, logger);
+        }
+        Err(err) => {
+            // The log settings come from the config, so the error goes to the default log.
+            Logger::new(&ServerConfig::default().log)
+                .log(&format!("Not starting the server: {:?}", err));
+        }
+    }
+}
+
+/// Binds the configured address, starts discovery and serves connections until the
+/// listener fails.
+pub fn run_server(config: ServerConfig, fs: Arc<dyn FileSystem>, logger: Arc<Logger>) {
+    let config = Arc::new(config);
+    logger.log(&format!(
+        "Effective settings:\n{}",
+        config.report().trim_end()
+    ));
+    for warning in config.warnings() {
+        logger.log(&format!("WARNING: {}", warning));
+    }
+
+    let Some(server) = bind_listener(&config, &logger) else {
+        return;
+    };
+    let address = match server.local_addr() {
+        Ok(address) => address,
+        Err(err) => {
+            logger.log(&format!("Could not read the bound address: {:?}", err));
+            return;
+        }
+    };
+    discovery::spawn(&config, address.port(), logger.clone());
+    logger.log(&format!(
+        "Started server on address {} with {} workers kept running and up to {} connections served at once",
+        address, config.limits.workers, config.limits.max_connections
+    ));
+    serve(server, config, fs, logger);
+}
+
+/// Accepts connections from `server` and hands them to a pool of workers.
+pub fn serve(
+    server: TcpListener,
+    config: Arc<ServerConfig>,
+    fs: Arc<dyn FileSystem>,
+    logger: Arc<Logger>,
+) {
+    let access = match AccessControl::new(&config.access) {
+        Ok(access) => Arc::new(access),
+        Err(err) => {
+            logger.log(&format!("Invalid access settings: {:?}", err));
+            return;
+        }
+    };
+    let pool = match WorkerPool::new(config.clone(), fs, access.clone(), logger.clone()) {
+        Ok(pool) => pool,
+        Err(err) => {
+            logger.log(&format!("Could not start workers: {:?}", err));
+            return;
+        }
+    };
+
+    for result in server.incoming() {
+        logger.log(&format!("Received incoming {:?}", result));
+
+        match result {
+            Ok(connection) => {
+                let peer = match connection.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(err) => {
+                        logger.log_error(&err);
+                        continue;
+                    }
+                };
+                if let Err(denied) = access.admit(peer.ip(), Instant::now()) {
+                    logger.log(&format!("Refusing connection from {}: {}", peer, denied));
+                    continue;
+                }
+                if let Err(connection) = pool.dispatch(connection) {
+                    logger.log(&format!(
+                        "Rejecting connection, server busy with {} connections",
+                        config.limits.max_connections
+                    ));
+                    let mut writer = &connection;
+                    let _ = ServerError::new(
+                        ErrorCode::Busy,
+                        format!(
+                            "Server busy: {} connections are already open, try again later",
+                            config.limits.max_connections
+                        ),
+                    )
+                    .write(&mut writer);
+                    let _ = writer.flush();
+                }
+            }
+            Err(err) => logger.log_error(&err),
+        }
+    }
+
+    logger.log("Shutting down server...");
+}
+
+/// Binds the listener, trying the configured port and then each fallback port. When all
+/// of them fail the whole round is retried with a growing delay, since the network may
+/// not be up yet at boot. Gives up and returns `None` after the configured attempts.
+fn bind_listener(config: &ServerConfig, logger: &Logger) -> Option<TcpListener> {
+    let retry = &config.bind_retry;
+    let ports = config.ports();
+    let mut delay = Duration::from_millis(retry.initial_delay_ms);
+    for attempt in 1..=retry.attempts {
+        for &port in &ports {
+            let address = format!("{}:{}", config.bind, port);
+            match TcpListener::bind(&address) {
+                Ok(listener) => return Some(listener),
+                Err(err) => logger.log(&format!(
+                    "Could not bind {} (attempt {} of {}): {}",
+                    address, attempt, retry.attempts, err
+                )),
+            }
+        }
+        if attempt < retry.attempts {
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(Duration::from_millis(retry.max_delay_ms));
+        }
+    }
+    logger.log(&format!(
+        "Giving up on binding {} after {} attempts, the server is not running",
+        config.bind, retry.attempts
+    ));
+    None
+}
+
+/// Worker threads fed from a shared queue of accepted connections. A connection is only
+/// queued when a worker is waiting for it. While every worker is busy, for example with
+/// idle sessions, connections up to the limit get a thread of their own, so no client
+/// waits for a reply behind another session. The pool size is only the number of threads
+/// kept between connections: up to `max_connections` threads serve at once.
+struct WorkerPool {
+    sender: mpsc::Sender<TcpStream>,
+    /// Workers waiting for the next connection.
+    idle: Arc<AtomicUsize>,
+    /// Connections currently being served.
+    active: Arc<AtomicUsize>,
+    max_connections: usize,
+    config: Arc<ServerConfig>,
+    fs: Arc<dyn FileSystem>,
+    access: Arc<AccessControl>,
+    logger: Arc<Logger>,
+}
+
+impl WorkerPool {
+    fn new(
+        config: Arc<ServerConfig>,
+        fs: Arc<dyn FileSystem>,
+        access: Arc<AccessControl>,
+        logger: Arc<Logger>,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<TcpStream>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = config.limits.workers.max(1);
+        let idle = Arc::new(AtomicUsize::new(workers));
+        let active = Arc::new(AtomicUsize::new(0));
+
+        for id in 0..workers {
+            let receiver = receiver.clone();
+            let idle = idle.clone();
+            let active = active.clone();
+            let config = config.clone();
+            let fs = fs.clone();
+            let access = access.clone();
+            let logger = logger.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || loop {
+                    let next = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    let Ok(connection) = next else {
+                        break;
+                    };
+                    handle_connection(&connection, &config, fs.as_ref(), &access, &logger);
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    idle.fetch_add(1, Ordering::SeqCst);
+                })?;
+        }
+
+        Ok(Self {
+            sender,
+            idle,
+            active,
+            max_connections: config.limits.max_connections,
+            config,
+            fs,
+            access,
+            logger,
+        })
+    }
+
+    /// Hands the connection to an idle worker, or to a thread of its own when every worker
+    /// is busy. Hands it back if the server is already at its connection limit.
+    fn dispatch(&self, connection: TcpStream) -> std::result::Result<(), TcpStream> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return Err(connection);
+        }
+        let claimed = self
+            .idle
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| {
+                idle.checked_sub(1)
+            });
+        if claimed.is_ok() {
+            return self.sender.send(connection).map_err(|err| {
+                self.active.fetch_sub(1, Ordering::SeqCst);
+                err.0
+            });
+        }
+
+        let connection = Arc::new(connection);
+        let spawned = {
+            let connection = connection.clone();
+            let active = self.active.clone();
+            let config = self.config.clone();
+            let fs = self.fs.clone();
+            let access = self.access.clone();
+            let logger = self.logger.clone();
+            std::thread::Builder::new()
+                .name("worker-extra".to_string())
+                .spawn(move || {
+                    handle_connection(&connection, &config, fs.as_ref(), &access, &logger);
+                    active.fetch_sub(1, Ordering::SeqCst);
+                })
+        };
+        if let Err(err) = spawned {
+            self.logger.log(&format!(
+                "Could not start a thread for the connection: {}",
+                err
+            ));
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            // The failed spawn dropped its handle, so this is the only one left.
+            if let Ok(connection) = Arc::try_unwrap(connection) {
+                return Err(connection);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection(
+    connection: &TcpStream,
+    config: &ServerConfig,
+    fs: &dyn FileSystem,
+    access: &AccessControl,
+    logger: &Logger,
+) {
+    if let Err(err) = process_connection(connection, config, fs, logger) {
+        if is_timeout(&err) {
+            logger.log(&format!("Closing connection that timed out: {}", err));
+            return;
+        }
+        logger.log_error(&err);
+        if is_protocol_error(&err) {
+            if let Ok(peer) = connection.peer_addr() {
+                if access.record_protocol_error(peer.ip(), Instant::now()) {
+                    logger.log(&format!(
+                        "Banning {} for {}s after repeated protocol errors",
+                        peer.ip(),
+                        config.access.ban_secs
+                    ));
+                }
+            }
+        }
+        if err.is::<StreamInterrupted>() {
+            return;
+        }
+        let mut writer = connection;
+        write_error_to_stream(&mut writer, &err, protocol::PROTOCOL_VERSION);
+        let _ = writer.flush();
+    }
+}
+
+/// Whether a failure was caused by the client breaking the protocol, as opposed to the
+/// network failing underneath it.
+fn is_protocol_error(err: &anyhow::Error) -> bool {
+    match err.root_cause().downcast_ref::<io::Error>() {
+        Some(err) => err.kind() == ErrorKind::InvalidData,
+        None => true,
+    }
+}
+
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.root_cause()
+        .downcast_ref::<io::Error>()
+        .is_some_and(|err| matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// Serves every request sent over a single connection.
+///
+/// Legacy clients open with a raw opcode and get exactly one request per connection.
+/// Clients that complete the handshake keep the connection open and may send any number
+/// of requests until they disconnect or send [`Request::Goodbye`]. The same reader is
+/// used for the whole connection so bytes buffered past one request are not lost.
+fn process_connection(
+    connection: &TcpStream,
+    config: &ServerConfig,
+    fs: &dyn FileSystem,
+    logger: &Logger,
+) -> Result<()> {
+    logger.log(&format!(
+        "Handling connection {:?}",
+        connection.local_addr()
+    ));
+
+    connection.set_read_timeout(config.timeouts.read())?;
+    connection.set_write_timeout(config.timeouts.write())?;
+    let mut reader = BufReader::new(connection);
+    let mut writer = BufWriter::new(connection);
+
+    let Some(opcode) = protocol::read_opcode(&mut reader)? else {
+        return Ok(());
+    };
+    if opcode != protocol::HANDSHAKE_MAGIC[0] {
+        if config.auth.enabled() {
+            logger.log("Rejecting legacy client, authentication is required");
+            ServerError::new(
+                ErrorCode::AuthenticationFailed,
+                "This server requires authentication, update the client",
+            )
+            .write(&mut writer)?;
+            writer.flush()?;
+            return Ok(());
+        }
+        let request = Request::read(opcode, &mut reader)?;
+        let version = protocol::PROTOCOL_VERSION;
+        return serve_request(
+            request,
+            &mut reader,
+            &mut writer,
+            version,
+            config,
+            fs,
+            logger,
+        );
+    }
+
+    let accepted = handshake(&mut reader, &mut writer, config, logger)?;
+    writer.flush()?;
+    let Some(version) = accepted else {
+        return Ok(());
+    };
+
+    let mut requests = 0;
+    loop {
+        // Waiting between requests is allowed for longer than stalling inside one.
+        connection.set_read_timeout(config.timeouts.idle())?;
+        let Some(opcode) = protocol::read_opcode(&mut reader)? else {
+            break;
+        };
+        connection.set_read_timeout(config.timeouts.read())?;
+        let request = Request::read(opcode, &mut reader)?;
+        let goodbye = request == Request::Goodbye;
+        serve_request(
+            request,
+            &mut reader,
+            &mut writer,
+            version,
+            config,
+            fs,
+            logger,
+        )?;
+        requests += 1;
+        if goodbye {
+            break;
+        }
+    }
+
+    logger.log(&format!("Connection closed after {} requests", requests));
+    Ok(())
+}
+
+/// Failure after part of a response was sent or part of a request body was received. The
+/// two sides can no longer find the next frame boundary, so the connection is closed
+/// instead of sending an error frame.
+#[derive(Debug)]
+struct StreamInterrupted(anyhow::Error);
+
+impl std::fmt::Display for StreamInterrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream interrupted")
+    }
+}
+
+/// The failure underneath stays the root cause, so a timeout or network error partway
+/// through a stream is classified like one outside of it.
+impl std::error::Error for StreamInterrupted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// Processes a request and writes either its response or an error response framed for
+/// the client's protocol `version`. Only failures to talk to the client are returned.
+fn serve_request<R: Read, W: Write>(
+    request: Request,
+    reader: &mut R,
+    writer: &mut W,
+    version: u16,
+    config: &ServerConfig,
+    fs: &dyn FileSystem,
+    logger: &Logger,
+) -> Result<()> {
+    if let Err(err) = process_request(request, reader, writer, config, fs, logger) {
+        if err.is::<StreamInterrupted>() {
+            return Err(err);
+        }
+        logger.log_error(&err);
+        write_error_to_stream(writer, &err, version);
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn process_request<R: Read, W: Write>(
+    request: Request,
+    reader: &mut R,
+    writer: &mut W,
+    config: &ServerConfig,
+    fs: &dyn FileSystem,
+    logger: &Logger,
+) -> Result<()> {
+    logger.log(&format!("Received request {:?}", request));
+
+    match &request {
+        Request::Exists { path } => {
+            // The response has no status byte to carry an error, so an invalid path is
+            // reported as missing.
+            let exists = paths::resolve(&config.data_root, path).is_ok_and(|path| fs.exists(&path));
+            writer.write_u8(if exists { 1 } else { 0 })?
+        }
+        Request::Read { path } => {
+            let path = paths::resolve(&config.data_root, path)?;
+            let length = stream_file(fs, &path, writer)?;
+            logger.log(&format!(
+                "Sent file of size {} from path {}",
+                length,
+                path.display()
+            ));
+        }
+        Request::List { path, glob } => {
+            let dir = RequestPath::parse(path)?;
+            send_listing(fs, &config.data_root, &dir, glob, writer, logger)?;
+        }
+        Request::Goodbye => protocol::write_ok(writer)?,
+        Request::ReadRange {
+            path,
+            offset,
+            length,
+        } => {
+            let path = paths::resolve(&config.data_root, path)?;
+            stream_file_range(fs, &path, *offset, *length, writer)?;
+            logger.log(&format!(
+                "Sent {} bytes at offset {} from path {}",
+                length,
+                offset,
+                path.display()
+            ));
+        }
+        Request::Stat { path } => {
+            let stat = stat_path(fs, paths::resolve(&config.data_root, path)?)?;
+            protocol::write_ok(writer)?;
+            stat.write(writer)?;
+        }
+        Request::StatBatch { paths } => {
+            let paths = paths
+                .iter()
+                .map(|path| paths::resolve(&config.data_root, path))
+                .collect::<Result<Vec<_>, _>>()?;
+            send_stats(fs, &paths, writer)?;
+        }
+        Request::Hash { path } => {
+            let hash = hash_file(fs, paths::resolve(&config.data_root, path)?)?;
+            protocol::write_ok(writer)?;
+            writer.write_all(&hash)?;
+        }
+        Request::HashBatch { paths } => {
+            let paths = paths
+                .iter()
+                .map(|path| paths::resolve(&config.data_root, path))
+                .collect::<Result<Vec<_>, _>>()?;
+            send_hashes(fs, &paths, writer)?;
+        }
+        Request::ReadIfChanged { path, hash } => {
+            let path = paths::resolve(&config.data_root, path)?;
+            let sent = read_if_changed(fs, &path, hash, writer)?;
+            logger.log(&format!(
+                "{} {}",
+                if sent {
+                    "Sent changed file"
+                } else {
+                    "Client already has"
+                },
+                path.display()
+            ));
+        }
+        Request::Write { path, length } => {
+            let path = match mod_fs::resolve_entry(&config.mod_root, path) {
+                Ok(path) => path,
+                Err(err) => {
+                    drain(reader, *length)?;
+                    return Err(err.into());
+                }
+            };
+            let hash = receive_file(fs, reader, *length, &path)?;
+            protocol::write_ok(writer)?;
+            writer.write_u64(*length)?;
+            writer.write_all(&hash)?;
+            logger.log(&format!("Wrote {} bytes to {}", length, path.display()));
+        }
+        Request::DeleteFile { path } => {
+            mod_fs::delete_file(fs, &mod_fs::resolve_entry(&config.mod_root, path)?)?;
+            protocol::write_ok(writer)?;
+        }
+        Request::DeleteDirectory { path, recursive } => {
+            let path = mod_fs::resolve_entry(&config.mod_root, path)?;
+            mod_fs::delete_directory(fs, &path, *recursive)?;
+            protocol::write_ok(writer)?;
+        }
+        Request::Rename { from, to } => {
+            let from = mod_fs::resolve_entry(&config.mod_root, from)?;
+            let to = mod_fs::resolve_entry(&config.mod_root, to)?;
+            mod_fs::rename(fs, &from, &to)?;
+            protocol::write_ok(writer)?;
+        }
+        Request::MakeDirectory { path } => {
+            mod_fs::make_directory(fs, &mod_fs::resolve_entry(&config.mod_root, path)?)?;
+            protocol::write_ok(writer)?;
+        }
+        Request::ModList { path, glob } => {
+            let dir = mod_fs::parse(&config.mod_root, path)?;
+            send_listing(fs, &config.mod_root, &dir, glob, writer, logger)?;
+        }
+        Request::ModStatBatch { paths } => {
+            let paths = paths
+                .iter()
+                .map(|path| mod_fs::resolve(&config.mod_root, path))
+                .collect::<Result<Vec<_>, _>>()?;
+            send_stats(fs, &paths, writer)?;
+        }
+        Request::ModHashBatch { paths } => {
+            let paths = paths
+                .iter()
+                .map(|path| mod_fs::resolve(&config.mod_root, path))
+                .collect::<Result<Vec<_>, _>>()?;
+            send_hashes(fs, &paths, writer)?;
+        }
+        Request::ListDir { path } => {
+            let path = paths::resolve(&config.data_root, path)?;
+            let entries = list_dir(fs, &path)?;
+            logger.log(&format!(
+                "Listed {} entries in dir {}",
+                entries.len(),
+                path.display()
+            ));
+            protocol::write_ok(writer)?;
+            writer.write_u32(entries.len() as u32)?;
+            for entry in entries {
+                entry.write(writer)?;
+            }
+        }
+    }
+
+    logger.log(&format!("Successfully processed request {:?}", request));
+    Ok(())
+}
+
+/// Answers a handshake frame whose first magic byte has already been consumed. When a
+/// token is configured the client must also answer the auth challenge.
+/// Returns the client's protocol version if it was accepted and may continue with
+/// requests.
+fn handshake<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &ServerConfig,
+    logger: &Logger,
+) -> Result<Option<u16>> {
+    let client_version = protocol::read_client_hello(reader)?;
+    let status = if (protocol::MIN_PROTOCOL_VERSION..=protocol::PROTOCOL_VERSION)
+        .contains(&client_version)
+    {
+        HandshakeStatus::Accepted
+    } else {
+        HandshakeStatus::UnsupportedVersion
+    };
+    logger.log(&format!(
+        "Handshake from client protocol version {} -> {:?}",
+        client_version, status
+    ));
+
+    if status != HandshakeStatus::Accepted || !config.auth.enabled() {
+        ServerHello::new(status).write(writer)?;
+        return Ok((status == HandshakeStatus::Accepted).then_some(client_version));
+    }
+
+    let challenge = auth::new_challenge();
+    ServerHello::auth_required(challenge).write(writer)?;
+    writer.flush()?;
+    let mut response = [0u8; 32];
+    reader.read_exact(&mut response)?;
+    if !auth::verify(&config.auth.token, &challenge, &response) {
+        return Err(
+            ServerError::new(ErrorCode::AuthenticationFailed, "Authentication failed").into(),
+        );
+    }
+    protocol::write_ok(writer)?;
+    Ok(Some(client_version))
+}
+
+/// Writes the error frame of `err`, without its code and context for clients older than
+/// [`protocol::CODED_ERRORS_VERSION`].
+fn write_error_to_stream<W: Write>(writer: &mut W, err: &anyhow::Error, version: u16) {
+    let err = ServerError::from_anyhow(err);
+    let _ = if version < protocol::CODED_ERRORS_VERSION {
+        err.write_message_only(writer)
+    } else {
+        err.write(writer)
+    };
+}
+
+/// Writes a read response for the file at `path`. The length comes from the file's
+/// metadata and the contents are streamed in [`READ_CHUNK_SIZE`] chunks, so memory use
+/// does not grow with the size of the file.
+fn stream_file<P: AsRef<Path>, W: Write>(
+    fs: &dyn FileSystem,
+    path: P,
+    writer: &mut W,
+) -> Result<u64> {
+    let (mut file, length) = open_file(fs, path.as_ref())?;
+    protocol::write_ok(writer)?;
+    send_contents(&mut *file, writer, length)?;
+    Ok(length)
+}
+
+/// Writes a read response holding `length` bytes of the file at `path` starting at
+/// `offset`. Ranges that do not fit inside the file are rejected before anything is sent.
+fn stream_file_range<P: AsRef<Path>, W: Write>(
+    fs: &dyn FileSystem,
+    path: P,
+    offset: u64,
+    length: u64,
+    writer: &mut W,
+) -> Result<()> {
+    let path = path.as_ref();
+    let (mut file, size) = open_file(fs, path)?;
+    match offset.checked_add(length) {
+        Some(end) if end <= size => {}
+        _ => {
+            return Err(ServerError::new(
+                ErrorCode::InvalidRequest,
+                format!(
+                    "Range of {} bytes at offset {} is outside of the file ({} bytes)",
+                    length, offset, size
+                ),
+            )
+            .with_context(path.display().to_string())
+            .into())
+        }
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    protocol::write_ok(writer)?;
+    send_contents(&mut *file, writer, length)
+}
+
+/// Writes a conditional read response, sending the file only if its hash differs from
+/// `cached`. Returns whether the contents were sent.
+///
+/// The hash and the contents come from one open handle, so a file replaced in between is
+/// never sent under the hash of the one it replaced.
+fn read_if_changed<P: AsRef<Path>, W: Write>(
+    fs: &dyn FileSystem,
+    path: P,
+    cached: &FileHash,
+    writer: &mut W,
+) -> Result<bool> {
+    let (mut file, _) = open_file(fs, path.as_ref())?;
+    let (hash, length) = hash_contents(&mut *file)?;
+    if &hash == cached {
+        protocol::write_ok(writer)?;
+        writer.write_u8(0)?;
+        return Ok(false);
+    }
+
+    file.rewind()?;
+    protocol::write_ok(writer)?;
+    writer.write_u8(1)?;
+    writer.write_all(&hash)?;
+    send_contents(&mut *file, writer, length)?;
+    Ok(true)
+}
+
+/// Writes the `length` byte request body to `path` and returns its SHA-256.
+///
+/// Parent directories are created as needed. The body goes to a temporary file next to
+/// the destination that is renamed over it once complete, so a failed upload never leaves
+/// a truncated file behind. If the file cannot be written the rest of the body is still
+/// consumed, keeping the connection usable for the error response.
+fn receive_file<R: Read, P: AsRef<Path>>(
+    fs: &dyn FileSystem,
+    reader: &mut R,
+    length: u64,
+    path: P,
+) -> Result<FileHash> {
+    let path = path.as_ref();
+    let Some(file_name) = path.file_name() else {
+        drain(reader, length)?;
+        return Err(ServerError::new(ErrorCode::InvalidPath, "Not a file path")
+            .with_context(path.display().to_string())
+            .into());
+    };
+    let mut temp_name = file_name.to_os_string();
+    temp_name.push(format!(
+        ".{}.astra-tmp",
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = match create_file(fs, path, &temp_path) {
+        Ok(file) => file,
+        Err(err) => {
+            drain(reader, length)?;
+            return Err(err);
+        }
+    };
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    let mut remaining = length;
+    let mut write_error = None;
+    while remaining > 0 {
+        let chunk = remaining.min(READ_CHUNK_SIZE as u64) as usize;
+        if let Err(err) = reader.read_exact(&mut buffer[..chunk]) {
+            drop(file);
+            let _ = fs.remove_file(&temp_path);
+            return Err(StreamInterrupted(err.into()).into());
+        }
+        remaining -= chunk as u64;
+        hasher.update(&buffer[..chunk]);
+        if write_error.is_none() {
+            if let Err(err) = file.write_all(&buffer[..chunk]) {
+                write_error = Some(err);
+            }
+        }
+    }
+
+    let result = match write_error {
+        Some(err) => Err(err),
+        None => file.sync(),
+    };
+    drop(file);
+    if let Err(err) = result.and_then(|_| fs.replace(&temp_path, path)) {
+        let _ = fs.remove_file(&temp_path);
+        return Err(err.into());
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn create_file(fs: &dyn FileSystem, path: &Path, temp_path: &Path) -> Result<Box<dyn WriteFile>> {
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    if fs.metadata(path).is_ok_and(|metadata| metadata.is_dir) {
+        return Err(is_a_directory(path).into());
+    }
+    fs.create(temp_path)
+        .with_context(|| path.display().to_string())
+}
+
+/// Discards the next `length` bytes of a request body.
+fn drain<R: Read>(reader: &mut R, length: u64) -> Result<()> {
+    let drained = io::copy(&mut reader.take(length), &mut io::sink())
+        .map_err(|err| StreamInterrupted(err.into()))?;
+    if drained < length {
+        return Err(StreamInterrupted(io::Error::from(ErrorKind::UnexpectedEof).into()).into());
+    }
+    Ok(())
+}
+
+/// Computes the SHA-256 of a file, reading it in [`READ_CHUNK_SIZE`] chunks.
+fn hash_file<P: AsRef<Path>>(fs: &dyn FileSystem, path: P) -> Result<FileHash> {
+    let (mut file, _) = open_file(fs, path.as_ref())?;
+    Ok(hash_contents(&mut *file)?.0)
+}
+
+/// Reads `file` to the end, returning the SHA-256 and length of what was read.
+fn hash_contents(file: &mut dyn ReadFile) -> io::Result<(FileHash, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    let mut length = 0;
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                hasher.update(&buffer[..read]);
+                length += read as u64;
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok((hasher.finalize().into(), length))
+}
+
+/// The hash of the file at `path`, or `None` when there is no file there. A directory
+/// counts as missing rather than failing the whole batch it was asked for in.
+fn hash_file_if_exists<P: AsRef<Path>>(fs: &dyn FileSystem, path: P) -> Result<Option<FileHash>> {
+    let path = path.as_ref();
+    if fs.metadata(path).is_ok_and(|metadata| metadata.is_dir) {
+        return Ok(None);
+    }
+    match hash_file(fs, path) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(_) if !fs.exists(path) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn open_file(fs: &dyn FileSystem, path: &Path) -> Result<(Box<dyn ReadFile>, u64)> {
+    let metadata = fs
+        .metadata(path)
+        .with_context(|| path.display().to_string())?;
+    if metadata.is_dir {
+        return Err(is_a_directory(path).into());
+    }
+    let file = fs.open(path).with_context(|| path.display().to_string())?;
+    Ok((file, metadata.len))
+}
+
+fn is_a_directory(path: &Path) -> ServerError {
+    ServerError::new(ErrorCode::WrongKind, "Is a directory")
+        .with_context(path.display().to_string())
+}
+
+fn not_a_directory(path: &Path) -> ServerError {
+    ServerError::new(ErrorCode::WrongKind, "Not a directory")
+        .with_context(path.display().to_string())
+}
+
+/// Writes the length and contents of a file. A failure partway through interrupts the
+/// response rather than producing an error frame.
+fn send_contents<W: Write>(file: &mut dyn ReadFile, writer: &mut W, length: u64) -> Result<()> {
+    writer.write_u64(length)?;
+    copy_exact(file, writer, length).map_err(|err| StreamInterrupted(err.into()))?;
+    Ok(())
+}
+
+/// Copies exactly `length` bytes through a single fixed-size buffer.
+fn copy_exact<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    length: u64,
+) -> io::Result<()> {
+    let mut buffer = vec![0u8; READ_CHUNK_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = remaining.min(READ_CHUNK_SIZE as u64) as usize;
+        let read = match reader.read(&mut buffer[..chunk]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("File ended {} bytes before its reported length", remaining),
+                ))
+            }
+            Ok(read) => read,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buffer[..read])?;
+        remaining -= read as u64;
+    }
+    Ok(())
+}
+
+/// Describes the entry at `path`. A missing entry is a regular result, while any other
+/// failure to read the metadata is an error.
+fn stat_path<P: AsRef<Path>>(fs: &dyn FileSystem, path: P) -> Result<FileStat> {
+    let metadata = match fs.metadata(path.as_ref()) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(FileStat::MISSING),
+        Err(err) => return Err(err.into()),
+    };
+    let modified = metadata
+        .modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs());
+    Ok(if metadata.is_dir {
+        FileStat {
+            kind: EntryKind::Directory,
+            size: 0,
+            modified,
+        }
+    } else {
+        FileStat {
+            kind: EntryKind::File,
+            size: metadata.len,
+            modified,
+        }
+    })
+}
+
+/// Writes a List response for the files below `dir` of `root`, as paths relative to
+/// `root`. The glob is matched against paths relative to `dir`.
+fn send_listing<W: Write>(
+    fs: &dyn FileSystem,
+    root: &str,
+    dir: &RequestPath,
+    glob: &str,
+    writer: &mut W,
+    logger: &Logger,
+) -> Result<()> {
+    let filter = GlobFilter::parse(glob)?;
+    let path = dir.under(root);
+
+    let mut paths = HashSet::new();
+    list_files(fs, Path::new(root), &path, &mut paths)?;
+    let total = paths.len();
+    if !filter.is_empty() {
+        paths.retain(|entry| {
+            entry
+                .strip_prefix(dir.relative())
+                .is_ok_and(|relative| filter.matches(relative))
+        });
+    }
+
+    logger.log(&format!(
+        "Listed {} paths from dir {}, {} matched glob '{}'",
+        total,
+        path.display(),
+        paths.len(),
+        glob
+    ));
+
+    protocol::write_ok(writer)?;
+    writer.write_u64(paths.len() as u64)?;
+    for path in paths {
+        writeln!(writer, "{}", path.display())?;
+    }
+    Ok(())
+}
+
+/// Writes a StatBatch response, one stat record per path.
+fn send_stats<W: Write>(fs: &dyn FileSystem, paths: &[PathBuf], writer: &mut W) -> Result<()> {
+    let stats = paths
+        .iter()
+        .map(|path| stat_path(fs, path))
+        .collect::<Result<Vec<_>>>()?;
+    protocol::write_ok(writer)?;
+    writer.write_u32(stats.len() as u32)?;
+    for stat in stats {
+        stat.write(writer)?;
+    }
+    Ok(())
+}
+
+/// Writes a HashBatch response, one hash record per path.
+fn send_hashes<W: Write>(fs: &dyn FileSystem, paths: &[PathBuf], writer: &mut W) -> Result<()> {
+    let hashes = paths
+        .iter()
+        .map(|path| hash_file_if_exists(fs, path))
+        .collect::<Result<Vec<_>>>()?;
+    protocol::write_ok(writer)?;
+    writer.write_u32(hashes.len() as u32)?;
+    for hash in hashes {
+        writer.write_u8(hash.is_some() as u8)?;
+        writer.write_all(&hash.unwrap_or_default())?;
+    }
+    Ok(())
+}
+
+/// The immediate children of the directory at `path`, sorted by name.
+fn list_dir(fs: &dyn FileSystem, path: &Path) -> Result<Vec<DirEntry>> {
+    let metadata = fs
+        .metadata(path)
+        .with_context(|| path.display().to_string())?;
+    if !metadata.is_dir {
+        return Err(not_a_directory(path).into());
+    }
+    let mut entries = fs
+        .read_dir(path)
+        .with_context(|| path.display().to_string())?
+        .into_iter()
+        .filter_map(|entry| {
+            let name = entry.path.file_name()?.to_string_lossy().into_owned();
+            Some(stat_path(fs, &entry.path).map(|stat| DirEntry { name, stat }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Collects the files under `dir` recursively, as paths relative to `root`.
+fn list_files<P: AsRef<Path>>(
+    fs: &dyn FileSystem,
+    root: &Path,
+    dir: P,
+    output: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let dir = dir.as_ref();
+    if fs.metadata(dir).is_ok_and(|metadata| metadata.is_dir) {
+        for entry in fs.read_dir(dir)? {
+            if entry.is_dir {
+                list_files(fs, root, entry.path, output)?;
+            } else if let Ok(relative) = entry.path.strip_prefix(root) {
+                output.insert(relative.to_path_buf());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::Denied;
+    use crate::config::{
+        AccessConfig, AuthConfig, BindRetryConfig, LimitsConfig, LogConfig, TimeoutsConfig,
+    };
+    use crate::fs::{HostFs, SkylineFs};
+    use crate::protocol::ReadExt;
+    use std::fs::File;
+
+    fn test_logger() -> Logger {
+        Logger::new(&LogConfig {
+            file: String::new(),
+            append: false,
+        })
+    }
+
+    fn bind_config(ports: &[u16], attempts: u32) -> ServerConfig {
+        ServerConfig {
+            bind: "127.0.0.1".to_string(),
+            port: ports[0],
+            fallback_ports: ports[1..].to_vec(),
+            bind_retry: BindRetryConfig {
+                attempts,
+                initial_delay_ms: 1,
+                max_delay_ms: 2,
+            },
+            ..ServerConfig::default()
+        }
+    }
+
+    fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn bind_falls_back_to_the_next_free_port() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let fallback_port = free_port();
+        let config = bind_config(&[taken_port, fallback_port], 1);
+        let listener = bind_listener(&config, &test_logger()).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), fallback_port);
+    }
+
+    #[test]
+    fn bind_retries_until_a_port_is_released() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let config = ServerConfig {
+            bind_retry: BindRetryConfig {
+                attempts: 10,
+                initial_delay_ms: 200,
+                max_delay_ms: 400,
+            },
+            ..bind_config(&[taken_port], 10)
+        };
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(taken);
+        });
+        let listener = bind_listener(&config, &test_logger()).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), taken_port);
+    }
+
+    #[test]
+    fn bind_gives_up_after_the_configured_attempts() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        assert!(bind_listener(&bind_config(&[taken_port], 3), &test_logger()).is_none());
+    }
+
+    #[test]
+    fn invalid_config_files_stop_the_server_from_starting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "port = 0\n\n[auth]\ntokn = \"secret\"\n").unwrap();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            run_with_config_file(path.to_str().unwrap(), Arc::new(SkylineFs));
+            let _ = sender.send(());
+        });
+        // Serving would never return, so returning shows the defaults weren't used.
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    /// Serves a single connection on a loopback port in the background.
+    fn serve_one(config: ServerConfig) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (connection, _) = listener.accept().unwrap();
+            let access = AccessControl::new(&config.access).unwrap();
+            handle_connection(&connection, &config, &SkylineFs, &access, &test_logger());
+        });
+        TcpStream::connect(address).unwrap()
+    }
+
+    /// Serves connections on a loopback port in the background.
+    fn serve_in_background(config: ServerConfig) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(
+                listener,
+                Arc::new(config),
+                Arc::new(SkylineFs),
+                Arc::new(test_logger()),
+            )
+        });
+        address
+    }
+
+    fn limits_config(workers: usize, max_connections: usize) -> ServerConfig {
+        ServerConfig {
+            limits: LimitsConfig {
+                workers,
+                max_connections,
+            },
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Connects and completes the handshake, or returns the busy frame sent instead of the
+    /// server's hello.
+    fn open_session(address: std::net::SocketAddr) -> std::result::Result<TcpStream, ServerError> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // A busy server may have closed already, which would fail a hello written in parts.
+        let mut hello = Vec::new();
+        protocol::write_client_hello(&mut hello, protocol::PROTOCOL_VERSION).unwrap();
+        let _ = stream.write_all(&hello);
+        let mut first = [0; 1];
+        stream.peek(&mut first).unwrap();
+        if first[0] == protocol::STATUS_ERROR {
+            stream.read_u8().unwrap();
+            return Err(ServerError::read(&mut stream).unwrap());
+        }
+        assert_eq!(
+            ServerHello::read(&mut stream).unwrap().status,
+            HandshakeStatus::Accepted
+        );
+        Ok(stream)
+    }
+
+    #[test]
+    fn connections_past_the_workers_are_served_without_waiting() {
+        let address = serve_in_background(limits_config(1, 2));
+        let _first = open_session(address).unwrap();
+        // The only worker is held by the first session, so this one can't be queued.
+        let _second = open_session(address).unwrap();
+        assert_eq!(open_session(address).unwrap_err().code, ErrorCode::Busy);
+    }
+
+    #[test]
+    fn closed_connections_free_their_slot() {
+        let address = serve_in_background(limits_config(1, 2));
+        let first = open_session(address).unwrap();
+        let second = open_session(address).unwrap();
+        assert_eq!(open_session(address).unwrap_err().code, ErrorCode::Busy);
+        drop(first);
+        drop(second);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut sessions = Vec::new();
+        while sessions.len() < 2 {
+            match open_session(address) {
+                Ok(session) => sessions.push(session),
+                Err(err) => {
+                    assert_eq!(err.code, ErrorCode::Busy);
+                    assert!(Instant::now() < deadline, "slots were never freed");
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            }
+        }
+        assert_eq!(open_session(address).unwrap_err().code, ErrorCode::Busy);
+    }
+
+    fn auth_config(token: &str) -> ServerConfig {
+        ServerConfig {
+            auth: AuthConfig {
+                token: token.to_string(),
+            },
+            ..ServerConfig::default()
+        }
+    }
+
+    /// Runs the handshake as a client holding `token` and returns the server's verdict.
+    fn authenticate(stream: &mut TcpStream, token: &str) -> u8 {
+        protocol::write_client_hello(stream, protocol::PROTOCOL_VERSION).unwrap();
+        let hello = ServerHello::read(stream).unwrap();
+        assert_eq!(hello.status, HandshakeStatus::AuthRequired);
+        stream
+            .write_all(&auth::respond(token, &hello.challenge.unwrap()))
+            .unwrap();
+        stream.read_u8().unwrap()
+    }
+
+    #[test]
+    fn clients_with_the_token_are_served() {
+        let mut stream = serve_one(auth_config("secret"));
+        assert_eq!(authenticate(&mut stream, "secret"), protocol::STATUS_OK);
+        Request::Goodbye.write(&mut stream).unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_OK);
+    }
+
+    #[test]
+    fn clients_with_the_wrong_token_are_disconnected() {
+        let mut stream = serve_one(auth_config("secret"));
+        assert_eq!(authenticate(&mut stream, "guess"), protocol::STATUS_ERROR);
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(String::from_utf8_lossy(&rest).contains("Authentication failed"));
+    }
+
+    #[test]
+    fn legacy_clients_are_refused_when_auth_is_enabled() {
+        let mut stream = serve_one(auth_config("secret"));
+        Request::Read {
+            path: "xml/Person.xml".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_ERROR);
+    }
+
+    #[test]
+    fn protocol_errors_ban_the_peer() {
+        let config = ServerConfig {
+            access: AccessConfig {
+                max_protocol_errors: 1,
+                ..AccessConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let access = AccessControl::new(&config.access).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"AXYZ\0\x01").unwrap();
+        let (connection, peer) = listener.accept().unwrap();
+
+        handle_connection(&connection, &config, &SkylineFs, &access, &test_logger());
+        assert!(matches!(
+            access.admit(peer.ip(), Instant::now()),
+            Err(Denied::Banned(_))
+        ));
+    }
+
+    #[test]
+    fn clients_that_stop_reading_midway_are_not_banned() {
+        let dir = tempfile::tempdir().unwrap();
+        File::create(dir.path().join("big.bin"))
+            .unwrap()
+            .set_len(64 << 20)
+            .unwrap();
+        let config = ServerConfig {
+            data_root: dir.path().to_str().unwrap().to_string(),
+            access: AccessConfig {
+                max_protocol_errors: 1,
+                ..AccessConfig::default()
+            },
+            timeouts: TimeoutsConfig {
+                write_secs: 1,
+                ..TimeoutsConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let access = AccessControl::new(&config.access).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        Request::Read {
+            path: "big.bin".to_string(),
+        }
+        .write(&mut client)
+        .unwrap();
+        let (connection, peer) = listener.accept().unwrap();
+
+        // The client never reads, so the download stalls once the socket buffers fill.
+        handle_connection(&connection, &config, &SkylineFs, &access, &test_logger());
+        assert_eq!(access.admit(peer.ip(), Instant::now()), Ok(()));
+    }
+
+    #[test]
+    fn failures_are_sent_as_coded_error_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stream = serve_one(ServerConfig {
+            data_root: dir.path().to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        });
+        protocol::write_client_hello(&mut stream, protocol::PROTOCOL_VERSION).unwrap();
+        ServerHello::read(&mut stream).unwrap();
+
+        Request::Read {
+            path: "xml/Missing.xml".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_ERROR);
+        let err = ServerError::read(&mut stream).unwrap();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert_eq!(
+            err.context.as_deref(),
+            Some(dir.path().join("xml/Missing.xml").to_str().unwrap())
+        );
+
+        Request::Read {
+            path: "../outside".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_ERROR);
+        assert_eq!(
+            ServerError::read(&mut stream).unwrap().code,
+            ErrorCode::InvalidPath
+        );
+    }
+
+    #[test]
+    fn version_1_sessions_get_message_only_error_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.xml"), b"a").unwrap();
+        let mut stream = serve_one(ServerConfig {
+            data_root: dir.path().to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        });
+        protocol::write_client_hello(&mut stream, 1).unwrap();
+        let hello = ServerHello::read(&mut stream).unwrap();
+        assert_eq!(hello.status, HandshakeStatus::Accepted);
+
+        Request::Read {
+            path: "Missing.xml".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_ERROR);
+        let message = stream.read_u64().unwrap();
+        stream.read_exact(&mut vec![0; message as usize]).unwrap();
+
+        // The next response follows the message directly.
+        Request::Exists {
+            path: "a.xml".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), 1);
+    }
+
+    #[test]
+    fn versions_before_the_minimum_are_refused() {
+        let mut stream = serve_one(ServerConfig::default());
+        protocol::write_client_hello(&mut stream, protocol::MIN_PROTOCOL_VERSION - 1).unwrap();
+        let hello = ServerHello::read(&mut stream).unwrap();
+        assert_eq!(hello.status, HandshakeStatus::UnsupportedVersion);
+    }
+
+    #[test]
+    fn silent_clients_are_disconnected() {
+        let mut stream = serve_one(ServerConfig {
+            timeouts: TimeoutsConfig {
+                read_secs: 1,
+                ..TimeoutsConfig::default()
+            },
+            ..ServerConfig::default()
+        });
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn idle_sessions_are_disconnected() {
+        let mut stream = serve_one(ServerConfig {
+            timeouts: TimeoutsConfig {
+                idle_secs: 1,
+                ..TimeoutsConfig::default()
+            },
+            ..ServerConfig::default()
+        });
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        protocol::write_client_hello(&mut stream, protocol::PROTOCOL_VERSION).unwrap();
+        ServerHello::read(&mut stream).unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn handshake_skips_the_challenge_without_a_token() {
+        let mut stream = serve_one(ServerConfig::default());
+        protocol::write_client_hello(&mut stream, protocol::PROTOCOL_VERSION).unwrap();
+        let hello = ServerHello::read(&mut stream).unwrap();
+        assert_eq!(hello.status, HandshakeStatus::Accepted);
+        assert_eq!(hello.challenge, None);
+    }
+
+    #[test]
+    fn list_dir_returns_immediate_children_with_their_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("xml/nested")).unwrap();
+        std::fs::write(dir.path().join("xml/nested/deep.xml"), b"deep").unwrap();
+        std::fs::write(dir.path().join("b.xml"), [0u8; 42]).unwrap();
+
+        let entries = list_dir(&SkylineFs, dir.path()).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.stat.kind, entry.stat.size))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("b.xml", EntryKind::File, 42),
+                ("xml", EntryKind::Directory, 0)
+            ]
+        );
+
+        let err = list_dir(&SkylineFs, &dir.path().join("b.xml")).unwrap_err();
+        assert_eq!(ServerError::from_anyhow(&err).code, ErrorCode::WrongKind);
+        let err = list_dir(&SkylineFs, &dir.path().join("missing")).unwrap_err();
+        assert_eq!(ServerError::from_anyhow(&err).code, ErrorCode::NotFound);
+    }
+
+    #[test]
+    fn mod_requests_look_at_the_mod_root() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("xml")).unwrap();
+        std::fs::write(dir.path().join("xml/a.xml"), b"a").unwrap();
+        let config = ServerConfig {
+            mod_root: dir.path().to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        };
+        let requests = [
+            Request::ModList {
+                path: String::new(),
+                glob: "*.xml".to_string(),
+            },
+            Request::ModStatBatch {
+                paths: vec!["xml".to_string()],
+            },
+            Request::ModHashBatch {
+                paths: vec![
+                    "xml/a.xml".to_string(),
+                    "xml/b.xml".to_string(),
+                    "xml".to_string(),
+                ],
+            },
+        ];
+        let mut response = Vec::new();
+        for request in requests {
+            let (mut reader, logger) = (io::empty(), test_logger());
+            process_request(
+                request,
+                &mut reader,
+                &mut response,
+                &config,
+                &SkylineFs,
+                &logger,
+            )
+            .unwrap();
+        }
+
+        let mut expected = vec![protocol::STATUS_OK];
+        expected.write_u64(1).unwrap();
+        expected.extend_from_slice(b"xml/a.xml\n");
+        expected.push(protocol::STATUS_OK);
+        expected.write_u32(1).unwrap();
+        stat_path(&SkylineFs, dir.path().join("xml"))
+            .unwrap()
+            .write(&mut expected)
+            .unwrap();
+        expected.push(protocol::STATUS_OK);
+        expected.write_u32(3).unwrap();
+        expected.push(1);
+        expected.extend_from_slice(&Sha256::digest(b"a"));
+        for _ in 0..2 {
+            expected.push(0);
+            expected.extend_from_slice(&[0; 32]);
+        }
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn writes_need_a_mod_root_and_stay_inside_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mods = dir.path().join("mods");
+        let write = |config: &ServerConfig, path: &str| {
+            let request = Request::Write {
+                path: path.to_string(),
+                length: 3,
+            };
+            let (mut body, logger) = (&b"abc"[..], test_logger());
+            let err = process_request(
+                request,
+                &mut body,
+                &mut Vec::new(),
+                config,
+                &SkylineFs,
+                &logger,
+            )
+            .unwrap_err();
+            assert!(body.is_empty());
+            ServerError::from_anyhow(&err).code
+        };
+
+        assert_eq!(
+            write(&ServerConfig::default(), "xml/a.xml"),
+            ErrorCode::PermissionDenied
+        );
+        let config = ServerConfig {
+            mod_root: mods.to_str().unwrap().to_string(),
+            ..ServerConfig::default()
+        };
+        for path in ["../escape.xml", "xml/../../escape.xml"] {
+            assert_eq!(write(&config, path), ErrorCode::InvalidPath);
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn serves_a_host_directory() {
+        let romfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir(romfs.path().join("xml")).unwrap();
+        std::fs::write(romfs.path().join("xml/Person.xml"), b"<Person/>").unwrap();
+        let config = ServerConfig {
+            data_root: romfs.path().to_str().unwrap().to_string(),
+            mod_root: String::new(),
+            ..ServerConfig::default()
+        };
+        let fs = HostFs::new([romfs.path()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(
+                listener,
+                Arc::new(config),
+                Arc::new(fs),
+                Arc::new(test_logger()),
+            )
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        protocol::write_client_hello(&mut stream, protocol::PROTOCOL_VERSION).unwrap();
+        ServerHello::read(&mut stream).unwrap();
+        Request::Read {
+            path: "xml/Person.xml".to_string(),
+        }
+        .write(&mut stream)
+        .unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_OK);
+        assert_eq!(stream.read_u64().unwrap(), 9);
+        let mut contents = [0; 9];
+        stream.read_exact(&mut contents).unwrap();
+        assert_eq!(&contents, b"<Person/>");
+
+        Request::Write {
+            path: "xml/Person.xml".to_string(),
+            length: 1,
+        }
+        .write(&mut stream)
+        .unwrap();
+        stream.write_all(b"x").unwrap();
+        assert_eq!(stream.read_u8().unwrap(), protocol::STATUS_ERROR);
+        assert_eq!(
+            ServerError::read(&mut stream).unwrap().code,
+            ErrorCode::PermissionDenied
+        );
+    }
+
+    /// Sink that keeps only what a test needs to check: the total size, the largest
+    /// single write and the bytes at a few chosen offsets.
+    struct CheckingSink {
+        written: u64,
+        largest_write: usize,
+        probes: Vec<(u64, Option<u8>)>,
+    }
+
+    impl CheckingSink {
+        fn new(offsets: &[u64]) -> Self {
+            Self {
+                written: 0,
+                largest_write: 0,
+                probes: offsets.iter().map(|offset| (*offset, None)).collect(),
+            }
+        }
+    }
+
+    impl Write for CheckingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let start = self.written;
+            let end = start + buf.len() as u64;
+            for (offset, value) in &mut self.probes {
+                if (start..end).contains(offset) {
+                    *value = Some(buf[(*offset - start) as usize]);
+                }
+            }
+            self.written = end;
+            self.largest_write = self.largest_write.max(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Peer that accepts `accept` bytes and then stalls until the socket timeout.
+    struct Stalled {
+        accept: usize,
+    }
+
+    impl Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.accept == 0 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let written = buf.len().min(self.accept);
+            self.accept -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn timeouts_midway_through_a_stream_are_timeouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let download = stream_file(&SkylineFs, &path, &mut Stalled { accept: 10 }).unwrap_err();
+        let mut body = (&b"abc"[..]).chain(Stalled { accept: 0 });
+        let upload =
+            receive_file(&SkylineFs, &mut body, 10, dir.path().join("up.txt")).unwrap_err();
+        for err in [download, upload] {
+            assert!(err.is::<StreamInterrupted>());
+            assert!(is_timeout(&err));
+            assert!(!is_protocol_error(&err));
+        }
+    }
+
+    #[test]
+    fn stream_file_sends_status_length_and_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Person.xml");
+        std::fs::write(&path, b"<Book/>").unwrap();
+
+        let mut output = Vec::new();
+        assert_eq!(stream_file(&SkylineFs, &path, &mut output).unwrap(), 7);
+        assert_eq!(output, b"\0\0\0\0\0\0\0\0\x07<Book/>");
+    }
+
+    #[test]
+    fn stream_file_streams_large_files_in_fixed_chunks() {
+        const SIZE: u64 = 384 * 1024 * 1024;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.bundle");
+
+        // Sparse file with a few marker bytes, so the test needs neither the disk space
+        // nor the memory of a real bundle.
+        let markers = [(0, 0x11), (SIZE / 2 + 3, 0x22), (SIZE - 1, 0x33)];
+        let mut file = File::create(&path).unwrap();
+        file.set_len(SIZE).unwrap();
+        for (offset, value) in markers {
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&[value]).unwrap();
+        }
+        drop(file);
+
+        // The response header is 9 bytes long, so file offsets shift by 9 in the output.
+        let offsets: Vec<u64> = markers.iter().map(|(offset, _)| offset + 9).collect();
+        let mut sink = CheckingSink::new(&offsets);
+        assert_eq!(stream_file(&SkylineFs, &path, &mut sink).unwrap(), SIZE);
+
+        assert_eq!(sink.written, SIZE + 9);
+        assert!(sink.largest_write <= READ_CHUNK_SIZE);
+        for ((_, expected), (_, actual)) in markers.iter().zip(&sink.probes) {
+            assert_eq!(*actual, Some(*expected));
+        }
+    }
+
+    #[test]
+    fn stream_file_fails_before_writing_for_directories_and_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut output = Vec::new();
+        assert!(stream_file(&SkylineFs, dir.path(), &mut output).is_err());
+        assert!(stream_file(&SkylineFs, dir.path().join("missing"), &mut output).is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn stream_file_range_sends_only_the_slice() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("header.bundle");
+        std::fs::write(&path, b"UnityFS\0header").unwrap();
+
+        let mut output = Vec::new();
+        stream_file_range(&SkylineFs, &path, 8, 6, &mut output).unwrap();
+        assert_eq!(output, b"\0\0\0\0\0\0\0\0\x06header");
+
+        output.clear();
+        stream_file_range(&SkylineFs, &path, 14, 0, &mut output).unwrap();
+        assert_eq!(output, [0; 9]);
+    }
+
+    #[test]
+    fn stream_file_range_rejects_ranges_outside_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("small.bundle");
+        std::fs::write(&path, [0u8; 10]).unwrap();
+
+        let mut output = Vec::new();
+        for (offset, length) in [(11, 0), (5, 6), (u64::MAX, 1), (1, u64::MAX)] {
+            let err =
+                stream_file_range(&SkylineFs, &path, offset, length, &mut output).unwrap_err();
+            assert!(err.to_string().contains("outside of"));
+        }
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn stat_path_describes_files_directories_and_missing_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Person.xml");
+        std::fs::write(&path, [0u8; 42]).unwrap();
+
+        let stat = stat_path(&SkylineFs, &path).unwrap();
+        assert_eq!(stat.kind, EntryKind::File);
+        assert_eq!(stat.size, 42);
+        assert!(stat.modified.is_some());
+
+        let stat = stat_path(&SkylineFs, dir.path()).unwrap();
+        assert_eq!(stat.kind, EntryKind::Directory);
+        assert_eq!(stat.size, 0);
+
+        assert_eq!(
+            stat_path(&SkylineFs, dir.path().join("missing")).unwrap(),
+            FileStat::MISSING
+        );
+    }
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hash_file_computes_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, b"abc").unwrap();
+
+        assert_eq!(hex(&hash_file(&SkylineFs, &path).unwrap()), ABC_SHA256);
+        assert!(hash_file_if_exists(&SkylineFs, &path).unwrap().is_some());
+        assert_eq!(
+            hash_file_if_exists(&SkylineFs, dir.path().join("missing")).unwrap(),
+            None
+        );
+        assert_eq!(hash_file_if_exists(&SkylineFs, dir.path()).unwrap(), None);
+        assert!(hash_file(&SkylineFs, dir.path()).is_err());
+    }
+
+    #[test]
+    fn read_if_changed_skips_matching_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, b"abc").unwrap();
+        let hash = hash_file(&SkylineFs, &path).unwrap();
+
+        let mut output = Vec::new();
+        assert!(!read_if_changed(&SkylineFs, &path, &hash, &mut output).unwrap());
+        assert_eq!(output, [0, 0]);
+
+        output.clear();
+        assert!(read_if_changed(&SkylineFs, &path, &[0; 32], &mut output).unwrap());
+        assert_eq!(output[..2], [0, 1]);
+        assert_eq!(output[2..34], hash);
+        assert_eq!(output[34..], *b"\0\0\0\0\0\0\0\x03abc");
+    }
+
+    /// Filesystem on which every opened file is replaced with `replacement` right after the
+    /// open, as if an upload finished at that moment.
+    struct ReplacedOnOpen {
+        replacement: &'static [u8],
+    }
+
+    impl FileSystem for ReplacedOnOpen {
+        fn metadata(&self, path: &Path) -> io::Result<crate::fs::Metadata> {
+            SkylineFs.metadata(path)
+        }
+
+        fn open(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+            let file = SkylineFs.open(path)?;
+            let temp = path.with_extension("replacement");
+            std::fs::write(&temp, self.replacement)?;
+            std::fs::rename(&temp, path)?;
+            Ok(file)
+        }
+
+        fn read_dir(&self, path: &Path) -> io::Result<Vec<crate::fs::DirEntry>> {
+            SkylineFs.read_dir(path)
+        }
+
+        fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+            SkylineFs.create(path)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            SkylineFs.create_dir_all(path)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            SkylineFs.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> io::Result<()> {
+            SkylineFs.remove_dir(path)
+        }
+
+        fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+            SkylineFs.remove_dir_all(path)
+        }
+
+        fn replace(&self, from: &Path, to: &Path) -> io::Result<()> {
+            SkylineFs.replace(from, to)
+        }
+    }
+
+    #[test]
+    fn read_if_changed_sends_the_contents_it_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        std::fs::write(&path, b"abc").unwrap();
+        let fs = ReplacedOnOpen {
+            replacement: b"replaced",
+        };
+
+        let mut output = Vec::new();
+        assert!(read_if_changed(&fs, &path, &[0; 32], &mut output).unwrap());
+        assert_eq!(hex(&output[2..34]), ABC_SHA256);
+        assert_eq!(output[34..], *b"\0\0\0\0\0\0\0\x03abc");
+        assert_eq!(std::fs::read(&path).unwrap(), b"replaced");
+    }
+
+    #[test]
+    fn receive_file_creates_parents_and_replaces_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("xml/sub/abc.txt");
+
+        let mut body = &b"abcleftover"[..];
+        assert_eq!(
+            hex(&receive_file(&SkylineFs, &mut body, 3, &path).unwrap()),
+            ABC_SHA256
+        );
+        assert_eq!(body, b"leftover");
+        assert_eq!(std::fs::read(&path).unwrap(), b"abc");
+
+        receive_file(&SkylineFs, &mut &b"replaced"[..], 8, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"replaced");
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+
+    /// Request body that runs a whole upload of the same path before its own first byte is
+    /// read, while the outer upload's temporary file is open.
+    struct InterleavedBody<'a> {
+        body: &'a [u8],
+        nested: Option<PathBuf>,
+    }
+
+    impl Read for InterleavedBody<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if let Some(path) = self.nested.take() {
+                receive_file(&SkylineFs, &mut &b"second"[..], 6, path).unwrap();
+            }
+            self.body.read(buf)
+        }
+    }
+
+    #[test]
+    fn concurrent_uploads_of_one_path_use_separate_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        let mut body = InterleavedBody {
+            body: b"first!",
+            nested: Some(path.clone()),
+        };
+        receive_file(&SkylineFs, &mut body, 6, &path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"first!");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn receive_file_consumes_the_body_when_the_file_cannot_be_written() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), b"").unwrap();
+
+        let mut body = &b"abcnext"[..];
+        assert!(receive_file(&SkylineFs, &mut body, 3, dir.path().join("file/abc.txt")).is_err());
+        assert_eq!(body, b"next");
+
+        let mut body = &b"abcnext"[..];
+        assert!(receive_file(&SkylineFs, &mut body, 3, dir.path()).is_err());
+        assert_eq!(body, b"next");
+    }
+
+    #[test]
+    fn receive_file_interrupts_on_truncated_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc.txt");
+        let err = receive_file(&SkylineFs, &mut &b"ab"[..], 3, &path).unwrap_err();
+        assert!(err.is::<StreamInterrupted>());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn short_file_interrupts_the_response() {
+        let mut output = Vec::new();
+        let err = copy_exact(&mut &b"abc"[..], &mut output, 10).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(output, b"abc");
+    }
+}